@@ -0,0 +1,270 @@
+use crate::core::{Event, FileRecord, Progress};
+use crate::error::PathError;
+use crate::fs::{hash_file, normalize_path, AbsPath, FileSystem, OutDir};
+use error_stack::{Result, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Name of the cache file, stored in the base directory
+pub const CACHE_FILE: &str = ".txtpp-cache";
+
+/// Persistent state of builds
+///
+/// This stores the hashes of the inputs and outputs of every processed `.txtpp` file,
+/// so that files with unchanged inputs can be skipped in the next run with incremental builds,
+/// and outputs changed since the last run are not overwritten.
+#[derive(Debug, Clone)]
+pub struct BuildCache {
+    /// The base directory. Paths in the cache file are relative to this
+    base: PathBuf,
+    /// Where the outputs are
+    out_dir: OutDir,
+    /// The file system
+    fs: Arc<dyn FileSystem>,
+    /// Entries keyed by the `.txtpp` file
+    entries: BTreeMap<String, CacheEntry>,
+    /// If the cache file existed when loaded
+    existed: bool,
+}
+
+/// State of one `.txtpp` file from the last run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Hash of the `.txtpp` file
+    source: String,
+    /// The output file. This is empty in caches from older versions
+    #[serde(default)]
+    output_file: String,
+    /// Hash of the output file
+    output: Option<String>,
+    /// `include` and `after` targets and their hashes
+    deps: BTreeMap<String, Option<String>>,
+    /// `.txtpp` files that need to be processed before this file
+    txtpp_deps: BTreeSet<String>,
+    /// Files written by `temp` directives and their hashes
+    temps: BTreeMap<String, Option<String>>,
+}
+
+/// Files produced by a `.txtpp` file that no longer exists, according to the cache
+#[derive(Debug, Clone)]
+pub struct Orphan {
+    /// The deleted `.txtpp` file
+    pub source: PathBuf,
+    /// The output file
+    pub output: PathBuf,
+    /// If the output file is also the output of an existing `.txtpp` file, so it should be kept
+    pub shared_output: bool,
+    /// Hash of the output when it was generated
+    pub output_hash: Option<String>,
+    /// Files written by `temp` directives
+    pub temps: Vec<PathBuf>,
+}
+
+/// Result of checking a file against the cache
+#[derive(Debug)]
+pub enum CacheCheck {
+    /// The file needs to be processed
+    Dirty,
+    /// The file has dependencies that need to be processed before it can be checked
+    Deps(Vec<AbsPath>),
+    /// The file is up-to-date and can be skipped. The record from the last run is returned
+    Fresh(FileRecord),
+}
+
+impl BuildCache {
+    /// Load the cache from the base directory.
+    ///
+    /// An empty cache is returned if the cache file doesn't exist or cannot be parsed.
+    /// A cache file that cannot be parsed is reported as a warning.
+    pub fn load(base: &AbsPath, out_dir: &OutDir, progress: &Progress) -> Self {
+        let fs = base.fs_arc();
+        let base = base.as_path_buf().clone();
+        let path = base.join(CACHE_FILE);
+        let content = fs.read_to_string(&path).ok();
+        let existed = content.is_some();
+        let entries = content
+            .and_then(|content| match serde_json::from_str(&content) {
+                Ok(entries) => Some(entries),
+                Err(e) => {
+                    progress.notify(&Event::Warning {
+                        message: &format!("ignoring invalid cache file: {e}"),
+                    });
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            base,
+            out_dir: out_dir.clone(),
+            fs,
+            entries,
+            existed,
+        }
+    }
+
+    /// Save the cache to the base directory
+    ///
+    /// Nothing is written if the cache is empty and the cache file didn't exist,
+    /// so runs that don't process any file don't leave a cache file behind.
+    pub fn save(&self) -> Result<(), PathError> {
+        if self.entries.is_empty() && !self.existed {
+            return Ok(());
+        }
+        let path = self.base.join(CACHE_FILE);
+        let content = serde_json::to_string_pretty(&self.entries)
+            .change_context_lazy(|| PathError::from(&path))
+            .attach_printable("could not serialize cache")?;
+        self.fs
+            .write(&path, content.as_bytes())
+            .change_context_lazy(|| PathError::from(&path))
+            .attach_printable("could not write cache file")
+    }
+
+    /// Check if the input file needs to be processed.
+    ///
+    /// In the first pass, the `.txtpp` dependencies from the last run are returned if the input
+    /// is unchanged, since their outputs need to be fresh before the file itself can be checked.
+    pub fn check(&self, input: &AbsPath, is_first_pass: bool) -> CacheCheck {
+        match self.check_internal(input, is_first_pass) {
+            Ok(result) => result,
+            Err(e) => {
+                log::debug!("cache check failed for {input}: {e:?}");
+                CacheCheck::Dirty
+            }
+        }
+    }
+
+    fn check_internal(
+        &self,
+        input: &AbsPath,
+        is_first_pass: bool,
+    ) -> Result<CacheCheck, PathError> {
+        let entry = match self.entries.get(&self.key(input.as_path())) {
+            Some(entry) => entry,
+            None => return Ok(CacheCheck::Dirty),
+        };
+        if hash_file(self.fs(), input)?.as_ref() != Some(&entry.source) {
+            return Ok(CacheCheck::Dirty);
+        }
+        if is_first_pass && !entry.txtpp_deps.is_empty() {
+            let deps = entry
+                .txtpp_deps
+                .iter()
+                .map(|dep| input.share_base(self.base.join(dep)))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(CacheCheck::Deps(deps));
+        }
+        // a `.txtpp` file could be added or removed for a dependency
+        if self.txtpp_deps(entry.deps.keys().map(|dep| self.base.join(dep))) != entry.txtpp_deps {
+            return Ok(CacheCheck::Dirty);
+        }
+        let output = self.out_dir.output_of(input.as_path())?;
+        if hash_file(self.fs(), &output)? != entry.output {
+            return Ok(CacheCheck::Dirty);
+        }
+        for (path, hash) in entry.deps.iter().chain(entry.temps.iter()) {
+            if &hash_file(self.fs(), &self.base.join(path))? != hash {
+                return Ok(CacheCheck::Dirty);
+            }
+        }
+        Ok(CacheCheck::Fresh(FileRecord {
+            deps: entry.deps.keys().map(|p| self.base.join(p)).collect(),
+            temps: entry.temps.keys().map(|p| self.base.join(p)).collect(),
+        }))
+    }
+
+    /// Get the hash of the output of the input file from the last run
+    pub fn output_hash(&self, input: &AbsPath) -> Option<String> {
+        self.entries
+            .get(&self.key(input.as_path()))
+            .and_then(|entry| entry.output.clone())
+    }
+
+    /// Get the files produced by the `.txtpp` files that no longer exist.
+    ///
+    /// Temporary files that are also produced by existing `.txtpp` files are not included
+    pub fn orphans(&self) -> Result<Vec<Orphan>, PathError> {
+        let mut orphans = vec![];
+        let mut live_files = BTreeSet::new();
+        for (key, entry) in &self.entries {
+            let source = self.base.join(key);
+            let output = if entry.output_file.is_empty() {
+                self.out_dir.output_of(&source)?
+            } else {
+                self.base.join(&entry.output_file)
+            };
+            let temps = entry.temps.keys().map(|p| self.base.join(p));
+            if self.fs.exists(&source) {
+                live_files.insert(output);
+                live_files.extend(temps);
+                continue;
+            }
+            orphans.push(Orphan {
+                source,
+                output,
+                shared_output: false,
+                output_hash: entry.output.clone(),
+                temps: temps.collect(),
+            });
+        }
+        for orphan in &mut orphans {
+            orphan.shared_output = live_files.contains(&orphan.output);
+            orphan.temps.retain(|temp| !live_files.contains(temp));
+        }
+        Ok(orphans)
+    }
+
+    /// Remove the entry of a `.txtpp` file
+    pub fn remove(&mut self, input: &Path) {
+        self.entries.remove(&self.key(input));
+    }
+
+    /// Record the state of a file that was just processed
+    pub fn record(&mut self, input: &AbsPath, record: &FileRecord) -> Result<(), PathError> {
+        let source = hash_file(self.fs(), input)?.unwrap_or_default();
+        let output_file = self.out_dir.output_of(input.as_path())?;
+        let output = hash_file(self.fs(), &output_file)?;
+        let deps = record
+            .deps
+            .iter()
+            .map(|dep| Ok((self.key(dep), hash_file(self.fs(), dep)?)))
+            .collect::<Result<BTreeMap<_, _>, PathError>>()?;
+        let temps = record
+            .temps
+            .iter()
+            .map(|temp| Ok((self.key(temp), hash_file(self.fs(), temp)?)))
+            .collect::<Result<BTreeMap<_, _>, PathError>>()?;
+        let txtpp_deps = self.txtpp_deps(record.deps.iter().cloned());
+        self.entries.insert(
+            self.key(input.as_path()),
+            CacheEntry {
+                source,
+                output_file: self.key(&output_file),
+                output,
+                deps,
+                txtpp_deps,
+                temps,
+            },
+        );
+        Ok(())
+    }
+
+    /// Get the `.txtpp` files corresponding to the dependencies
+    fn txtpp_deps(&self, deps: impl Iterator<Item = PathBuf>) -> BTreeSet<String> {
+        deps.filter_map(|dep| self.out_dir.source_of(&dep, self.fs()))
+            .map(|p| self.key(&self.fs.canonicalize(&p).unwrap_or(p)))
+            .collect()
+    }
+
+    fn fs(&self) -> &dyn FileSystem {
+        self.fs.as_ref()
+    }
+
+    /// Get the key of a path in the cache, which is relative to the base directory if possible
+    fn key(&self, p: &Path) -> String {
+        let p = p.strip_prefix(&self.base).unwrap_or(p);
+        normalize_path(&p.display().to_string()).to_string()
+    }
+}
@@ -1,5 +1,10 @@
+use super::{ConfigFile, Observer};
 use crate::core::verbs;
+use crate::fs::{FileSystem, RealFs};
+use serde::Serialize;
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 /// Config for running txtpp
 ///
@@ -22,16 +27,150 @@ pub struct Config {
     pub shell_cmd: String,
     /// The input file/directories
     pub inputs: Vec<String>,
+    /// Directory to write the output files to, relative to `base_dir`.
+    ///
+    /// The tree under `base_dir` is mirrored in the output directory, and `include` or `after` targets
+    /// that are outputs of `.txtpp` files are read from there. [`Mode::Clean`] and [`Mode::Verify`] also
+    /// operate on the output directory. Temporary files from `temp` directives are still written next
+    /// to the `.txtpp` files. If `None`, the outputs are next to the `.txtpp` files.
+    pub out_dir: Option<PathBuf>,
     /// Whether to recursively process directories
     pub recursive: bool,
+    /// Patterns in `.gitignore` syntax that the `.txtpp` files found in directories must match one of.
+    ///
+    /// The patterns are relative to `base_dir`. If empty, all `.txtpp` files are included.
+    /// This doesn't apply to files given directly in `inputs`.
+    pub include: Vec<String>,
+    /// Patterns in `.gitignore` syntax for files and directories to skip when scanning directories.
+    ///
+    /// The patterns are relative to `base_dir`. Excluded directories are not scanned.
+    /// This doesn't apply to files and directories given directly in `inputs`.
+    pub exclude: Vec<String>,
+    /// Also honor `.gitignore` files when scanning directories.
+    ///
+    /// The [`IGNORE_FILE`](crate::IGNORE_FILE) (`.txtppignore`) is always honored. Ignore files
+    /// are read from `base_dir` and the directories under it. Patterns in a deeper ignore file take precedence,
+    /// and in the same directory, `.txtppignore` takes precedence over `.gitignore`.
+    pub gitignore: bool,
+    /// How to treat symbolic links. See [`SymlinkPolicy`]
+    pub symlinks: SymlinkPolicy,
+    /// The maximum depth of subdirectories to scan under the input directories when `recursive` is `true`.
+    ///
+    /// The input directories are at depth `0`. If `None`, there is no limit.
+    pub max_depth: Option<usize>,
     /// The number of threads to use
     pub num_threads: usize,
+    /// Run the commands of `run` directives in a file concurrently.
+    ///
+    /// A command starts when its directive is reached, and the file keeps being processed while it runs.
+    /// Before any directive other than `run`, `write` or the empty directive is executed, the commands
+    /// running are waited for, so a `temp` file is written after the commands before it finish,
+    /// and is seen by the commands after it. The outputs are written in the same order as the directives.
+    /// Use `run-seq` for commands that should not run concurrently with the ones around them.
+    ///
+    /// At most [`Config::num_threads`] commands run at the same time, across all files.
+    pub parallel_run: bool,
     /// The mode. See [`Mode]
     pub mode: Mode,
     /// The verbosity. See [`Verbosity`]
+    ///
+    /// This is only used by the default [`TerminalObserver`](crate::TerminalObserver) when `observer` is `None`.
     pub verbosity: Verbosity,
+    /// Receiver of the events during the run. See [`Observer`]
+    ///
+    /// If `None`, the status is printed to stderr with [`TerminalObserver`](crate::TerminalObserver).
+    pub observer: Option<Arc<dyn Observer>>,
+    /// Return the dependency graph in this format instead of the build plan in [`Mode::Plan`].
+    pub graph_format: Option<GraphFormat>,
+    /// Keep processing other files when a file fails.
+    ///
+    /// Files that depend on a failed file are skipped. The run fails at the end with
+    /// every failed and skipped file in [`TxtppError`](crate::error::TxtppError).
+    pub keep_going: bool,
     /// If the output files should have trailing newline
     pub trailing_newline: bool,
+    /// Skip files whose inputs are unchanged since the last run.
+    ///
+    /// The state of the last run is stored in `.txtpp-cache` in the base directory,
+    /// which is updated by every build. This only has effect when building.
+    pub incremental: bool,
+    /// Process every file even if it's up-to-date according to the cache,
+    /// and overwrite outputs that were changed since the last run.
+    ///
+    /// Without this, building fails if an output was changed since it was generated,
+    /// so changes made to it by hand are not lost. The cache is still updated.
+    pub force: bool,
+    /// Input files/directories that are always processed even if they are up-to-date according to the cache.
+    ///
+    /// This is useful for files with `run` directives that depend on something not tracked by txtpp.
+    pub always_dirty: Vec<String>,
+    /// In [`Mode::Clean`], remove the files produced by `.txtpp` files that were deleted, instead of cleaning the inputs.
+    ///
+    /// The output and temporary files of every `.txtpp` file are recorded in `.txtpp-cache` by every build,
+    /// so the files of a `.txtpp` file that was renamed or deleted since then are found from the cache.
+    /// The inputs are not used. Depfiles and source maps are also removed if `depfile` or `source_map` is `true`.
+    /// An output that was changed since it was generated is only removed if `force` is `true`.
+    pub orphans: bool,
+    /// With `orphans`, print the files that would be removed to stdout without removing them.
+    pub dry_run: bool,
+    /// Write a Makefile-syntax depfile next to each output, named as the output with `.d` appended.
+    ///
+    /// The depfile lists the `.txtpp` file and the files from `include` and `after` directives
+    /// as prerequisites of the output, like `gcc -MD`. The depfiles are also removed in [`Mode::Clean`].
+    pub depfile: bool,
+    /// Write a source map next to each output, named as the output with `.map` appended.
+    ///
+    /// The source map is JSON that maps ranges of lines in the output to where they are from:
+    /// lines of the `.txtpp` file, lines of an included file, or the output of a `run` or `write` directive.
+    /// See [`SourceMap`](crate::SourceMap) for the format. The source maps are also removed in [`Mode::Clean`].
+    pub source_map: bool,
+    /// Write line markers to outputs with these extensions, like `#line` in C, mapped to the format of the markers.
+    ///
+    /// In the format, `{line}` is replaced with the line number and `{file}` with the path, relative to `base_dir`,
+    /// of the `.txtpp` file or the included file that the next line is from. For example, `#line {line} "{file}"`
+    /// for `c` files. A marker is written before the output of each directive, and before lines of the `.txtpp` file
+    /// that don't follow the line before them. The output of `run` and `write` directives is marked with the line of
+    /// the directive.
+    ///
+    /// In [`Mode::Verify`], lines that are markers are ignored when comparing the outputs.
+    pub line_markers: HashMap<String, String>,
+    /// Add a banner with this text to the start of the outputs, such as [`DEFAULT_BANNER`](crate::DEFAULT_BANNER).
+    ///
+    /// `{file}` in the text is replaced with the path of the `.txtpp` file, relative to `base_dir`.
+    /// Each line of the text is a comment with the syntax of the output file's extension, like `// ` for `rs` files.
+    /// Outputs with an extension whose comment syntax is unknown, like `txt`, don't have the banner.
+    /// If the output starts with a shebang (`#!`), the banner is after the shebang line.
+    ///
+    /// The banner is part of the output, so [`Mode::Verify`] and [`Mode::InMemoryBuild`] compare it as well.
+    pub banner: Option<String>,
+    /// Environment variables set for the commands of `run` directives
+    pub env: HashMap<String, String>,
+    /// Load settings from [`CONFIG_FILE`](crate::CONFIG_FILE) (`txtpp.toml`) files, which override the fields of the config.
+    ///
+    /// The closest file in `base_dir` or its ancestors applies to all files. Paths in it are relative to
+    /// the directory of the file. Files in the directories under `base_dir` apply to the `.txtpp` files under them,
+    /// and override the ones above. Only the settings that can be different for each `.txtpp` file are used from them:
+    /// `shell`, `trailing-newline`, `parallel-run`, `line-markers`, `banner`, `env` and `defines`.
+    ///
+    /// Settings in `overrides` take precedence over the files.
+    pub config_files: bool,
+    /// Settings that take precedence over the `txtpp.toml` files, like the command line flags.
+    ///
+    /// These are applied even if `config_files` is `false`. Paths are relative to `base_dir`.
+    pub overrides: ConfigFile,
+    /// Print a unified diff between the output file and the fresh output when verifying fails.
+    ///
+    /// This only has effect in [`Mode::Verify`].
+    pub verify_diff: bool,
+    /// Write the diffs from verifying to this file as a patch, which can be applied to update the outputs.
+    ///
+    /// The path is relative to `base_dir`. This only has effect in [`Mode::Verify`] with `verify_diff` enabled.
+    pub patch_file: Option<PathBuf>,
+    /// The file system to read and write files in. See [`FileSystem`]
+    ///
+    /// Commands from `run` directives are still run in the real file system, with `base_dir`
+    /// or the directory of the `.txtpp` file as the working directory.
+    pub fs: Arc<dyn FileSystem>,
 }
 
 impl Default for Config {
@@ -41,25 +180,90 @@ impl Default for Config {
     /// - Running from the current directory
     /// - Using the platform-specific default shell
     /// - Processing the current directory
+    /// - Writing outputs next to the `.txtpp` files
     /// - Not recursively processing directories
+    /// - Including all `.txtpp` files not ignored by `.txtppignore` files
+    /// - Following symbolic links, but scanning each directory once
     /// - Using 4 threads
+    /// - Running the commands in a file one at a time
     /// - Building output files
-    /// - Regular verbosity
+    /// - Printing the status to stderr with regular verbosity
+    /// - Stopping at the first error
     /// - Output files have trailing newline
+    /// - Not using the incremental build cache
+    /// - Not writing depfiles, source maps, line markers or banners
+    /// - Not loading `txtpp.toml` files
+    /// - Not printing diffs when verifying
+    /// - Using the real file system
     fn default() -> Self {
         Self {
             base_dir: PathBuf::from("."),
             shell_cmd: "".to_string(),
             inputs: vec![".".to_string()],
+            out_dir: None,
             recursive: false,
+            include: vec![],
+            exclude: vec![],
+            gitignore: false,
+            symlinks: SymlinkPolicy::FollowOnce,
+            max_depth: None,
             num_threads: 4,
+            parallel_run: false,
             mode: Mode::Build,
             verbosity: Verbosity::Normal,
+            observer: None,
+            keep_going: false,
+            graph_format: None,
             trailing_newline: true,
+            incremental: false,
+            force: false,
+            always_dirty: vec![],
+            orphans: false,
+            dry_run: false,
+            depfile: false,
+            source_map: false,
+            line_markers: HashMap::new(),
+            banner: None,
+            env: HashMap::new(),
+            config_files: false,
+            overrides: ConfigFile::default(),
+            verify_diff: false,
+            patch_file: None,
+            fs: Arc::new(RealFs),
         }
     }
 }
 
+/// The format to export the dependency graph in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GraphFormat {
+    /// The DOT language of Graphviz
+    Dot,
+    /// JSON with every `.txtpp` file, its output, dependencies and temporary files
+    Json,
+}
+
+/// How symbolic links are treated
+///
+/// Paths are resolved to their targets, so a `.txtpp` file reached through a link is processed
+/// as the target file, and its output is next to the target. This also means that with both
+/// [`Follow`](Self::Follow) and [`FollowOnce`](Self::FollowOnce), a file or directory reached through
+/// several paths is only processed once. They only differ in how a loop is handled.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SymlinkPolicy {
+    /// Follow symbolic links. A directory link that loops back to a directory being scanned is an error
+    Follow,
+    /// Follow symbolic links, and skip directory links that loop back to a directory being scanned.
+    ///
+    /// This is the default.
+    FollowOnce,
+    /// Don't follow symbolic links.
+    ///
+    /// Links are skipped when scanning directories, and links in the inputs or as
+    /// `include` or `after` targets are errors.
+    Skip,
+}
+
 /// The verbosity config options
 #[derive(Debug, PartialEq, Clone)]
 pub enum Verbosity {
@@ -69,7 +273,8 @@ pub enum Verbosity {
 }
 
 /// The mode config options
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Mode {
     /// Build output files
     ///
@@ -87,15 +292,29 @@ pub enum Mode {
     ///
     /// Remove the output file and any temporary file the `.txtpp` input files may produce.
     /// Dependency is not automatically cleaned if they are not specified in the inputs.
+    /// With [`Config::orphans`], the files of `.txtpp` files that were deleted are removed instead.
     Clean,
     /// Verify output files are up to date
     ///
     /// In this mode, the output files will be compared against output from a fresh run.
     /// The run will fail if any output is different from the fresh output.
+    /// Enable [`Config::verify_diff`] to see what is different.
     ///
     /// Note that the temporary files are rebuilt in the process in order to generate the fresh output.
     /// However, temporary files that already have the same content will not be re-written to avoid changing the modification time.
     Verify,
+    /// Print the build plan without building
+    ///
+    /// The output files and temporary files that would be produced, the dependencies of each file,
+    /// and the order they would be built in are returned in [`RunReport::plan`](crate::RunReport::plan).
+    /// The CLI prints it to stdout. Nothing is written and no command is run,
+    /// so the plan also shows what would be deleted by [`Mode::Clean`].
+    ///
+    /// Since `run` directives are not executed, dependencies that can only be known by running
+    /// commands are not in the plan.
+    ///
+    /// Set [`Config::graph_format`] to get the dependency graph instead.
+    Plan,
 }
 
 impl Mode {
@@ -104,6 +323,7 @@ impl Mode {
             Self::Build | Self::InMemoryBuild => verbs::PROCESSING,
             Self::Clean => verbs::CLEANING,
             Self::Verify => verbs::VERIFYING,
+            Self::Plan => verbs::PLANNING,
         }
     }
     pub fn processed_verb(&self) -> &'static str {
@@ -111,6 +331,11 @@ impl Mode {
             Self::Build | Self::InMemoryBuild => verbs::PROCESSED,
             Self::Clean => verbs::CLEANED,
             Self::Verify => verbs::VERIFIED,
+            Self::Plan => verbs::PLANNED,
         }
     }
+    /// If the mode writes output files
+    pub fn is_build(&self) -> bool {
+        matches!(self, Self::Build | Self::InMemoryBuild)
+    }
 }
@@ -0,0 +1,292 @@
+use super::Config;
+use crate::core::DEFAULT_LINE_MARKER;
+use crate::error::PathError;
+use crate::fs::{normalize_path, FileSystem};
+use error_stack::{Result, ResultExt};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the project configuration file
+pub const CONFIG_FILE: &str = "txtpp.toml";
+
+/// Settings from a `txtpp.toml` file, or settings that override them like the command line flags
+///
+/// Settings that are `None` are not set. The keys in the file are the same as the command line flags:
+/// ```toml
+/// inputs = ["docs", "src"]
+/// recursive = true
+/// exclude = ["target"]
+/// threads = 8
+/// shell = "bash -c"
+/// trailing-newline = false
+/// line-markers = ["c", "glsl=#line {line}"]
+/// defines = ["DEBUG=1"]
+///
+/// [env]
+/// VERSION = "1.2.3"
+/// ```
+/// Paths are relative to the directory of the file. See [`Config::config_files`] for how the files are found.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConfigFile {
+    /// See [`Config::inputs`]
+    pub inputs: Option<Vec<String>>,
+    /// See [`Config::recursive`]
+    pub recursive: Option<bool>,
+    /// See [`Config::include`]
+    pub include: Option<Vec<String>>,
+    /// See [`Config::exclude`]
+    pub exclude: Option<Vec<String>>,
+    /// See [`Config::gitignore`]
+    pub gitignore: Option<bool>,
+    /// See [`Config::max_depth`]
+    pub max_depth: Option<usize>,
+    /// See [`Config::out_dir`]
+    pub out_dir: Option<PathBuf>,
+    /// See [`Config::num_threads`]
+    pub threads: Option<usize>,
+    /// See [`Config::keep_going`]
+    pub keep_going: Option<bool>,
+    /// See [`Config::incremental`]
+    pub incremental: Option<bool>,
+    /// See [`Config::depfile`]
+    pub depfile: Option<bool>,
+    /// See [`Config::source_map`]
+    pub source_map: Option<bool>,
+    /// See [`Config::shell_cmd`]
+    pub shell: Option<String>,
+    /// See [`Config::trailing_newline`]
+    pub trailing_newline: Option<bool>,
+    /// See [`Config::parallel_run`]
+    pub parallel_run: Option<bool>,
+    /// Extensions to write line markers for, like `c`, or `c=FORMAT` with the format.
+    ///
+    /// These are added to [`Config::line_markers`]
+    pub line_markers: Vec<String>,
+    /// See [`Config::banner`]
+    pub banner: Option<String>,
+    /// Environment variables for the commands. These are added to [`Config::env`]
+    pub env: BTreeMap<String, String>,
+    /// Environment variables for the commands like `NAME=VALUE`, added after `env`
+    pub defines: Vec<String>,
+}
+
+impl ConfigFile {
+    /// Load the config file
+    pub fn load(fs: &dyn FileSystem, path: &Path) -> Result<Self, PathError> {
+        let content = fs
+            .read_to_string(path)
+            .change_context_lazy(|| PathError::from(&path))
+            .attach_printable("could not read config file")?;
+        toml::from_str(&content)
+            .change_context_lazy(|| PathError::from(&path))
+            .attach_printable("could not parse config file")
+    }
+
+    /// Find the closest config file in the directory or its ancestors
+    pub fn discover(fs: &dyn FileSystem, dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|dir| dir.join(CONFIG_FILE))
+            .find(|path| fs.is_file(path))
+    }
+
+    /// Apply the settings that are set to the config. Paths are relative to [`Config::base_dir`]
+    pub fn apply_to(&self, config: &mut Config) {
+        macro_rules! apply {
+            ($($field:ident => $config_field:ident),* $(,)?) => {
+                $(
+                    if let Some(value) = &self.$field {
+                        config.$config_field = value.clone();
+                    }
+                )*
+            };
+        }
+        apply! {
+            inputs => inputs,
+            recursive => recursive,
+            include => include,
+            exclude => exclude,
+            gitignore => gitignore,
+            keep_going => keep_going,
+            threads => num_threads,
+            incremental => incremental,
+            depfile => depfile,
+            source_map => source_map,
+            shell => shell_cmd,
+            trailing_newline => trailing_newline,
+            parallel_run => parallel_run,
+        }
+        if self.max_depth.is_some() {
+            config.max_depth = self.max_depth;
+        }
+        if self.out_dir.is_some() {
+            config.out_dir = self.out_dir.clone();
+        }
+        if self.banner.is_some() {
+            config.banner = self.banner.clone();
+        }
+        config
+            .line_markers
+            .extend(self.line_markers.iter().map(|arg| {
+                let (ext, format) = arg.split_once('=').unwrap_or((arg, DEFAULT_LINE_MARKER));
+                (ext.trim_start_matches('.').to_string(), format.to_string())
+            }));
+        config.env.extend(self.env.clone());
+        config.env.extend(self.defines.iter().map(|define| {
+            let (name, value) = define.split_once('=').unwrap_or((define, ""));
+            (name.to_string(), value.to_string())
+        }));
+    }
+
+    /// Get the settings that can be different for each `.txtpp` file, which are the ones used in nested config files
+    pub(crate) fn per_file(&self) -> Self {
+        Self {
+            shell: self.shell.clone(),
+            trailing_newline: self.trailing_newline,
+            parallel_run: self.parallel_run,
+            line_markers: self.line_markers.clone(),
+            banner: self.banner.clone(),
+            env: self.env.clone(),
+            defines: self.defines.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Make the paths relative to `base` instead of `dir`, the directory of the file.
+    ///
+    /// `base` must be `dir` or under it. Patterns anchored to a path outside of `base` are removed,
+    /// since they can't match anything.
+    pub(crate) fn rebase(&self, dir: &Path, base: &Path) -> Self {
+        let relative = match base.strip_prefix(dir) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => return self.clone(),
+        };
+        let relative = normalize_path(&relative.display().to_string()).to_string();
+        let rebase_path = |p: &Path| {
+            let p = dir.join(p);
+            match p.strip_prefix(base) {
+                Ok(p) if p.as_os_str().is_empty() => PathBuf::from("."),
+                Ok(p) => p.to_path_buf(),
+                Err(_) => p,
+            }
+        };
+        let rebase_patterns = |patterns: &Vec<String>| {
+            patterns
+                .iter()
+                .filter_map(|pattern| rebase_pattern(pattern, &relative))
+                .collect()
+        };
+        Self {
+            inputs: self.inputs.as_ref().map(|inputs| {
+                inputs
+                    .iter()
+                    .map(|input| rebase_path(Path::new(input)).display().to_string())
+                    .collect()
+            }),
+            include: self.include.as_ref().map(rebase_patterns),
+            exclude: self.exclude.as_ref().map(rebase_patterns),
+            out_dir: self.out_dir.as_deref().map(rebase_path),
+            ..self.clone()
+        }
+    }
+}
+
+/// Apply the closest config file in the base directory or its ancestors if [`Config::config_files`] is enabled,
+/// then the overrides
+pub(crate) fn load_config_file(config: &mut Config, base: &Path) -> Result<(), PathError> {
+    if config.config_files {
+        if let Some(path) = ConfigFile::discover(config.fs.as_ref(), base) {
+            log::info!("using config file: {}", path.display());
+            let file = ConfigFile::load(config.fs.as_ref(), &path)?;
+            let dir = path.parent().unwrap_or(base);
+            file.rebase(dir, base).apply_to(config);
+        }
+    }
+    let overrides = config.overrides.clone();
+    overrides.apply_to(config);
+    Ok(())
+}
+
+/// Make a `.gitignore` pattern relative to the subdirectory `relative` instead
+fn rebase_pattern(pattern: &str, relative: &str) -> Option<String> {
+    let (negation, p) = match pattern.strip_prefix('!') {
+        Some(p) => ("!", p),
+        None => ("", pattern),
+    };
+    // a slash at the start or in the middle anchors the pattern to the directory
+    if !p.trim_end_matches('/').contains('/') {
+        return Some(pattern.to_string());
+    }
+    let p = p.trim_start_matches('/');
+    if p.starts_with("**/") {
+        return Some(pattern.to_string());
+    }
+    let rest = p.strip_prefix(relative)?.strip_prefix('/')?;
+    Some(format!("{negation}/{rest}"))
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let file: ConfigFile = toml::from_str(
+            "threads = 8\nshell = \"bash -c\"\nline-markers = [\"c\"]\n[env]\nA = \"1\"\n",
+        )
+        .unwrap();
+        assert_eq!(file.threads, Some(8));
+        assert_eq!(file.shell.as_deref(), Some("bash -c"));
+        assert_eq!(file.recursive, None);
+        assert_eq!(file.env.get("A").map(String::as_str), Some("1"));
+        assert!(toml::from_str::<ConfigFile>("unknown = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let file = ConfigFile {
+            threads: Some(8),
+            trailing_newline: Some(false),
+            line_markers: vec!["c".to_string(), ".glsl=#line {line}".to_string()],
+            env: BTreeMap::from([("A".to_string(), "1".to_string())]),
+            defines: vec!["A=2".to_string(), "B".to_string()],
+            ..Default::default()
+        };
+        let mut config = Config::default();
+        file.apply_to(&mut config);
+        assert_eq!(config.num_threads, 8);
+        assert!(!config.trailing_newline);
+        assert!(!config.recursive);
+        assert_eq!(config.line_markers["c"], DEFAULT_LINE_MARKER);
+        assert_eq!(config.line_markers["glsl"], "#line {line}");
+        assert_eq!(config.env["A"], "2");
+        assert_eq!(config.env["B"], "");
+    }
+
+    #[test]
+    fn test_rebase() {
+        let file = ConfigFile {
+            inputs: Some(vec!["docs".to_string(), "src".to_string()]),
+            exclude: Some(vec![
+                "target".to_string(),
+                "docs/gen/".to_string(),
+                "!/docs/keep".to_string(),
+                "src/gen".to_string(),
+                "**/tmp".to_string(),
+            ]),
+            out_dir: Some(PathBuf::from("docs/out")),
+            ..Default::default()
+        };
+        let file = file.rebase(Path::new("/repo"), Path::new("/repo/docs"));
+        assert_eq!(
+            file.inputs.unwrap(),
+            vec![".".to_string(), "/repo/src".to_string()]
+        );
+        assert_eq!(
+            file.exclude.unwrap(),
+            vec!["target", "/gen/", "!/keep", "**/tmp"]
+        );
+        assert_eq!(file.out_dir.unwrap(), PathBuf::from("out"));
+    }
+}
@@ -0,0 +1,209 @@
+use super::pp::{preprocess_stream, PpOpts};
+use super::{load_config_file, Config, Observer, SymlinkPolicy};
+use crate::error::{PpError, PpErrorKind, TxtppError};
+use crate::fs::{AbsPath, FileSystem, OutDir, RealFs, Shell};
+use crate::Mode;
+use error_stack::{Report, Result, ResultExt};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Options for preprocessing a single source with [`process_str`]
+///
+/// # Example
+/// ```no_run
+/// use txtpp::{process_str, ProcessOptions};
+///
+/// let options = ProcessOptions {
+///     work_dir: "path/to/includes".into(),
+///     ..Default::default()
+/// };
+/// let output = process_str("-TXTPP#include foo.txt\n", &options).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    /// Directory to resolve paths in directives and run commands in
+    pub work_dir: PathBuf,
+    /// The shell command to use. (e.g. `bash -c`). Empty string for platform-specific default shell
+    pub shell: String,
+    /// Name of the source, used in errors and in the `TXTPP_FILE` environment variable for `run` directives
+    pub name: String,
+    /// If the output should have trailing newline
+    pub trailing_newline: bool,
+    /// The file system to read included files and write temporary files in. See [`Config::fs`]
+    pub fs: Arc<dyn FileSystem>,
+    /// Receiver of [`Event::DirectiveExecuted`](super::Event::DirectiveExecuted) events. See [`Config::observer`]
+    pub observer: Option<Arc<dyn Observer>>,
+    /// Environment variables set for the commands. See [`Config::env`]
+    pub env: HashMap<String, String>,
+}
+
+impl Default for ProcessOptions {
+    /// Get the default options.
+    ///
+    /// This means:
+    /// - Current directory as the working directory
+    /// - Platform-specific default shell
+    /// - `<string>` as the name
+    /// - Trailing newline
+    /// - The real file system
+    /// - No observer
+    /// - No extra environment variables
+    fn default() -> Self {
+        Self {
+            work_dir: PathBuf::from("."),
+            shell: String::new(),
+            name: "<string>".to_string(),
+            trailing_newline: true,
+            fs: Arc::new(RealFs),
+            observer: None,
+            env: HashMap::new(),
+        }
+    }
+}
+
+/// Preprocess a txtpp source in memory and return the output
+///
+/// The source is not read from a file and the output is not written to a file.
+/// The files in `include` directives are read as they are on disk, relative to [`ProcessOptions::work_dir`],
+/// and `temp` directives still write to files.
+pub fn process_str(source: &str, options: &ProcessOptions) -> Result<String, PpError> {
+    let output = SharedBuf::default();
+    process(io::Cursor::new(source.to_string()), output.clone(), options)?;
+    let output = output.0.take();
+    String::from_utf8(output).map_err(|e| {
+        Report::new(e)
+            .change_context(make_error(options, PpErrorKind::Other))
+            .attach_printable("output is not valid UTF-8")
+    })
+}
+
+/// Preprocess a txtpp source from `input` and write the output to `output`
+///
+/// This is what `txtpp expand` uses to work as a filter. No output file is written.
+/// `name` is used in errors and in the `TXTPP_FILE` environment variable for `run` directives.
+///
+/// Paths in directives are resolved relative to [`Config::base_dir`], which is also where
+/// commands are run. Only the shell, trailing newline, environment variables, file system and observer options
+/// are used from the config, after applying the config file of `base_dir` if [`Config::config_files`] is enabled.
+/// Since there is no output file, the outputs of other `.txtpp` files are not built before
+/// they are included.
+pub fn expand<R, W>(input: R, name: &str, output: W, config: &Config) -> Result<(), TxtppError>
+where
+    R: BufRead + 'static,
+    W: Write + 'static,
+{
+    let mut config = config.clone();
+    let base = config
+        .fs
+        .canonicalize(&config.base_dir)
+        .unwrap_or_else(|_| config.base_dir.clone());
+    load_config_file(&mut config, &base).map_err(|e| {
+        e.change_context(TxtppError::default())
+            .attach_printable("cannot load config file")
+    })?;
+    let options = ProcessOptions {
+        work_dir: config.base_dir.clone(),
+        shell: config.shell_cmd.clone(),
+        name: name.to_string(),
+        trailing_newline: config.trailing_newline,
+        fs: Arc::clone(&config.fs),
+        observer: config.observer.clone(),
+        env: config.env.clone(),
+    };
+    process(input, output, &options).change_context(TxtppError::default())
+}
+
+fn process<R, W>(input: R, output: W, options: &ProcessOptions) -> Result<(), PpError>
+where
+    R: BufRead + 'static,
+    W: Write + 'static,
+{
+    let shell = Shell::new(&options.shell)
+        .map_err(|e| {
+            e.change_context(make_error(options, PpErrorKind::Other))
+                .attach_printable(format!(
+                    "cannot parse shell command: {cmd}",
+                    cmd = options.shell
+                ))
+        })?
+        .with_env(options.env.clone());
+    let work_dir = AbsPath::create_base(options.work_dir.clone(), Arc::clone(&options.fs))
+        .map_err(|e| {
+            e.change_context(make_error(options, PpErrorKind::Other))
+                .attach_printable("cannot resolve working directory")
+        })?;
+    let opts = PpOpts {
+        shell,
+        mode: Mode::Build,
+        trailing_newline: options.trailing_newline,
+        verify_diff: false,
+        out_dir: OutDir::new(),
+        observer: options.observer.clone(),
+        symlinks: SymlinkPolicy::FollowOnce,
+        run_pool: None,
+        source_map: false,
+        line_markers: Default::default(),
+        banner: None,
+    };
+    preprocess_stream(
+        &opts,
+        Box::new(input),
+        &options.name,
+        &work_dir,
+        Box::new(output),
+    )?;
+    Ok(())
+}
+
+fn make_error(options: &ProcessOptions, kind: PpErrorKind) -> PpError {
+    PpError {
+        kind,
+        file: options.name.clone(),
+        line: 0,
+    }
+}
+
+/// Output buffer that can be read after the preprocessor is done with it
+#[derive(Clone, Default)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    #[test]
+    fn test_process_str() {
+        let options = ProcessOptions::default();
+        let output = process_str("a\n-TXTPP#run echo hi\n", &options).unwrap();
+        assert_eq!(output, "a\nhi\n\n");
+        let options = ProcessOptions {
+            trailing_newline: false,
+            ..Default::default()
+        };
+        assert_eq!(process_str("a\n", &options).unwrap(), "a");
+    }
+
+    #[test]
+    fn test_process_str_error() {
+        let options = ProcessOptions {
+            name: "foo".to_string(),
+            ..Default::default()
+        };
+        let err = process_str("TXTPP#include does_not_exist\n", &options).unwrap_err();
+        assert_eq!(err.current_context().file, "foo");
+    }
+}
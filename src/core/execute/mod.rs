@@ -1,32 +1,52 @@
-use crate::core::{print_dep_map, verbs, DepManager, Progress};
-use crate::error::{PathError, PpError, TxtppError};
-use crate::fs::{AbsPath, Directory, Shell};
+use crate::core::{
+    make_depfile, relative_to, DepGraph, DepIndex, DepManager, FileRecord, LineMarker, Progress,
+};
+use crate::error::{Failure, PathError, PpError, TxtppError, VerifyDiff};
+use crate::fs::{AbsPath, Directory, OutDir, Shell};
 use error_stack::{Report, Result};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use std::sync::mpsc::TryRecvError;
 use std::sync::Arc;
-use std::time::Instant;
-use termcolor::Color;
+use std::time::{Duration, Instant};
 use threadpool::Builder;
 use threadpool::ThreadPool;
 
+mod cache;
+use cache::BuildCache;
+pub use cache::CACHE_FILE;
 mod config;
 pub use config::*;
+mod config_file;
+pub use config_file::*;
+mod expand;
+pub use expand::*;
 
+mod observer;
+pub use observer::*;
+mod orphans;
 mod pp;
-use pp::{preprocess, PpResult};
+use pp::{preprocess, PpOpts, PpResult};
+mod report;
+pub use report::*;
 mod resolve_inputs;
 use resolve_inputs::resolve_inputs;
 mod scan_dir;
-use scan_dir::scan_dir;
+pub use scan_dir::IGNORE_FILE;
+use scan_dir::{scan_dir, ScanFilter, ScanScope};
+mod watch;
+pub use watch::*;
+
+/// Extension appended to the output file name for its depfile
+const DEPFILE_EXT: &str = ".d";
 
 /// Run txtpp with the given config
 ///
 /// This is the main entry point for txtpp. It takes a [`Config`] and runs txtpp.
 /// If an error occurs, it will be printed to stderr.
 ///
-/// If you want to retrieve the error report without printing it, use [`Txtpp::run`].
+/// If you want to retrieve the error report without printing it, or the [`RunReport`], use [`Txtpp::run`].
 pub fn txtpp(config: Config) -> Result<(), TxtppError> {
     match Txtpp::run(config) {
         Ok(_) => Ok(()),
@@ -42,88 +62,221 @@ pub fn txtpp(config: Config) -> Result<(), TxtppError> {
 pub struct Txtpp {
     /// The Config
     config: Config,
-    /// The Shell
-    shell: Arc<Shell>,
+    /// The options for preprocessing
+    opts: Arc<PpOpts>,
+    /// The base directory
+    base: AbsPath,
+    /// Filters for scanning directories
+    filter: Arc<ScanFilter>,
     /// The Progress reporter
     progress: Progress,
     /// The ThreadPool
     threadpool: ThreadPool,
+    /// The ThreadPool for running commands in the background
+    run_pool: ThreadPool,
     /// The Sender for workers to send results back
     send: mpsc::Sender<TaskResult>,
     /// The Receiver for the main thread to receive results
     recv: mpsc::Receiver<TaskResult>,
+    /// The number of tasks whose results are not received yet
+    pending: usize,
     /// Files in the build system
     ///
     /// This is to track we don't unnecessarily process the same file twice in the first pass
     files: HashSet<AbsPath>,
+    /// Config files in the directories under the base directory, or `None` if there isn't one in the directory
+    nested_files: HashMap<PathBuf, Option<ConfigFile>>,
+    /// The options for the `.txtpp` files in the directories with nested config files
+    dir_opts: HashMap<PathBuf, Arc<PpOpts>>,
+    /// The cache from the last run, used to skip up-to-date files and to detect outputs changed since then.
+    ///
+    /// This is `None` when forced
+    cache: Option<Arc<BuildCache>>,
+    /// The cache to be saved at the end of the run
+    new_cache: Option<BuildCache>,
+    /// Files that are always processed regardless of the cache
+    always_dirty: Directory,
+    /// Records of processed files
+    index: DepIndex,
+    /// Files that are known to be up-to-date and will not be processed
+    up_to_date: HashSet<AbsPath>,
+    /// Directories scanned
+    dirs: HashSet<AbsPath>,
+    /// Diffs of the outputs that failed verification
+    diffs: Vec<VerifyDiff>,
+    /// Errors of the failed files when running with `keep_going`
+    errors: Option<Report<PpError>>,
+    /// Files that failed when running with `keep_going`
+    failures: Vec<Failure>,
+    /// Files skipped because they depend on failed files
+    skipped: Vec<String>,
+    /// Time spent processing each file so far
+    durations: HashMap<AbsPath, Duration>,
+    /// The report of the run
+    report: RunReport,
 }
 
 impl Txtpp {
     /// Internal run function
     ///
     /// This is what [`txtpp`] calls internally. The difference is that this function
-    /// returns the error instead of printing it.
-    pub fn run(config: Config) -> Result<(), TxtppError> {
+    /// returns the error instead of printing it, and returns a [`RunReport`] of the files processed.
+    pub fn run(config: Config) -> Result<RunReport, TxtppError> {
+        Self::new(config)?.execute()
+    }
+
+    fn new(mut config: Config) -> Result<Self, TxtppError> {
         log::info!("creating txtpp");
-        log::debug!("using config: {:?}", config);
 
-        let shell = Arc::new(Shell::new(&config.shell_cmd).map_err(|e| {
-            e.change_context(TxtppError).attach_printable(format!(
-                "cannot parse shell command: {cmd}",
-                cmd = config.shell_cmd
-            ))
-        })?);
+        let base =
+            AbsPath::create_base(config.base_dir.clone(), Arc::clone(&config.fs)).map_err(|e| {
+                e.change_context(TxtppError::default())
+                    .attach_printable("cannot resolve base directory")
+            })?;
+        load_config_file(&mut config, base.as_path()).map_err(|e| {
+            e.change_context(TxtppError::default())
+                .attach_printable("cannot load config file")
+        })?;
+        log::debug!("using config: {:?}", config);
+        let filter = ScanFilter::new(&base, &config).map_err(|e| {
+            e.change_context(TxtppError::default())
+                .attach_printable("cannot parse include or exclude patterns")
+        })?;
+        let out_dir = match &config.out_dir {
+            Some(out_dir) => {
+                let out_root = base.as_path().join(out_dir);
+                // the output directory is created when the outputs are written
+                let out_root = base.fs().canonicalize(&out_root).unwrap_or(out_root);
+                OutDir::with_root(base.as_path_buf().clone(), out_root)
+            }
+            None => OutDir::new(),
+        };
+        // commands are run in a separate pool, since the workers wait for them
+        let run_pool = Builder::new().num_threads(config.num_threads).build();
+        let opts = Arc::new(make_opts(&config, out_dir, &run_pool)?);
 
-        let progress = Progress::new(config.verbosity.clone());
+        let progress = Progress::new(observer_of(&config));
 
         let threadpool = Builder::new().num_threads(config.num_threads).build();
         let (send, recv) = mpsc::channel();
 
-        let mut runtime = Self {
+        Ok(Self {
             config,
-            shell,
+            opts,
+            base,
+            filter: Arc::new(filter),
             progress,
             threadpool,
+            run_pool,
             send,
             recv,
+            pending: 0,
             files: HashSet::new(),
-        };
+            nested_files: HashMap::new(),
+            dir_opts: HashMap::new(),
+            cache: None,
+            new_cache: None,
+            always_dirty: Directory::new(),
+            index: DepIndex::new(),
+            up_to_date: HashSet::new(),
+            dirs: HashSet::new(),
+            diffs: vec![],
+            errors: None,
+            failures: vec![],
+            skipped: vec![],
+            durations: HashMap::new(),
+            report: RunReport::default(),
+        })
+    }
 
-        let result = runtime.run_internal();
-        if result.is_err() {
-            let _ = runtime
-                .progress
-                .print_status(verbs::FAILED, "", Color::Red, false);
-            runtime.progress.has_error = true;
+    fn execute(&mut self) -> Result<RunReport, TxtppError> {
+        let start_time = Instant::now();
+        let mut result = self.run_internal();
+        self.finish_tasks();
+        if let Some(cache) = &self.new_cache {
+            // save the cache even if there's an error, so that the processed files are not processed again
+            let saved = cache.save().map_err(|e| {
+                e.change_context(TxtppError::default())
+                    .attach_printable("cannot save build cache")
+            });
+            result = result.and(saved);
+        }
+        if let (Mode::Verify, true, Some(patch_file)) = (
+            &self.config.mode,
+            self.config.verify_diff,
+            &self.config.patch_file,
+        ) {
+            // write the patch even if empty, so a patch from an earlier run is not mistaken as current
+            let patch = self
+                .diffs
+                .iter()
+                .map(|d| d.diff.as_str())
+                .collect::<String>();
+            let path = self.config.base_dir.join(patch_file);
+            let written = self.base.fs().write(&path, patch.as_bytes()).map_err(|e| {
+                Report::new(e)
+                    .change_context(TxtppError::default())
+                    .attach_printable(format!("cannot write patch file: `{}`", path.display()))
+            });
+            result = result.and(written);
+        }
+        if let Err(error) = &result {
+            self.progress.notify(&Event::Error { error });
         }
 
-        result
+        result?;
+        let mut report = std::mem::take(&mut self.report);
+        report.duration = start_time.elapsed();
+        self.progress.notify(&Event::Finished {
+            report: &report,
+            paths: self.progress.total_count,
+        });
+        Ok(report)
     }
 
     fn run_internal(&mut self) -> Result<(), TxtppError> {
-        let start_time = Instant::now();
-        let _ =
-            self.progress
-                .print_status(verbs::USING, &self.shell.to_string(), Color::Yellow, true);
-        let _ = self.progress.print_status(
-            verbs::USING,
-            &format!("{} thread(s)", self.config.num_threads),
-            Color::Yellow,
-            true,
-        );
-
-        let base_abs_path = AbsPath::create_base(self.config.base_dir.clone()).map_err(|e| {
-            e.change_context(TxtppError)
-                .attach_printable("cannot resolve base directory")
+        self.progress.notify(&Event::Started {
+            base: self.base.as_path(),
+            mode: &self.config.mode,
+            shell: &self.opts.shell.to_string(),
+            threads: self.config.num_threads,
+        });
+        if self.config.orphans && self.config.mode == Mode::Clean {
+            return self.clean_orphans();
+        }
+
+        let base_abs_path = self.base.clone();
+        let inputs: Directory = resolve_inputs(
+            &self.config.inputs,
+            &base_abs_path,
+            &self.opts.out_dir,
+            self.config.symlinks,
+        )
+        .map_err(|e| {
+            e.change_context(TxtppError::default())
+                .attach_printable("cannot resolve inputs")
         })?;
-        let inputs: Directory =
-            resolve_inputs(&self.config.inputs, &base_abs_path).map_err(|e| {
-                e.change_context(TxtppError)
-                    .attach_printable("cannot resolve inputs")
+        if self.config.mode.is_build() {
+            // the outputs are always recorded, so they are not overwritten if changed by hand
+            let cache = BuildCache::load(&base_abs_path, &self.opts.out_dir, &self.progress);
+            if !self.config.force {
+                self.cache = Some(Arc::new(cache.clone()));
+            }
+            self.new_cache = Some(cache);
+        }
+        if self.config.incremental && self.config.mode.is_build() {
+            self.always_dirty = resolve_inputs(
+                &self.config.always_dirty,
+                &base_abs_path,
+                &self.opts.out_dir,
+                self.config.symlinks,
+            )
+            .map_err(|e| {
+                e.change_context(TxtppError::default())
+                    .attach_printable("cannot resolve always-dirty inputs")
             })?;
+        }
         let mut dep_mgr = DepManager::new();
-        let mut file_count = 0;
-        let _ = self.progress.add_total(inputs.subdirs.len());
 
         // schedule input files
         for file in inputs.files {
@@ -131,54 +284,80 @@ impl Txtpp {
         }
         // schedule input directories
         for dir in inputs.subdirs {
-            self.execute_directory(dir.clone(), self.config.recursive);
+            let scope = self.filter.scope_of(&base_abs_path, &dir).map_err(|e| {
+                e.change_context(TxtppError::default())
+                    .attach_printable("cannot read ignore files")
+            })?;
+            self.execute_directory(dir, self.config.recursive, scope);
         }
 
-        loop {
-            let data = match self.recv.try_recv() {
-                Ok(data) => data,
-                Err(TryRecvError::Empty) => {
-                    if self.progress.is_done() {
-                        break;
-                    }
-                    // no data available, wait for a bit
-                    std::thread::sleep(std::time::Duration::from_millis(100));
-                    continue;
-                }
-                Err(TryRecvError::Disconnected) => {
-                    // workers are disconnected unexpectedly
-                    return Err(Report::new(TxtppError)
-                        .attach_printable("workers are disconnected unexpectedly."));
-                }
-            };
-
-            let _ = self.progress.add_done(1);
+        // every task sends exactly one result, so we are done when nothing is pending
+        while self.pending > 0 {
+            let data = self.recv.recv().map_err(|e| {
+                Report::new(e)
+                    .change_context(TxtppError::default())
+                    .attach_printable("workers are disconnected unexpectedly.")
+            })?;
+            self.pending -= 1;
+            self.progress.add_done(1);
 
             match data {
-                TaskResult::ScanDir(result) => {
+                TaskResult::Panicked(message) => {
+                    return Err(Report::new(TxtppError::default())
+                        .attach_printable(format!("worker panicked: {message}")));
+                }
+                TaskResult::ScanDir(dir, result) => {
                     log::info!("scanning directory done");
-                    let directory = result.map_err(|e| {
-                        self.progress.add_done_quiet(1);
-                        e.change_context(TxtppError)
+                    let (directory, scope) = result.map_err(|e| {
+                        e.change_context(TxtppError::default())
                             .attach_printable("cannot scan directory")
                     })?;
-                    let _ = self.progress.add_total(directory.subdirs.len());
+                    self.progress.notify(&Event::DirScanned {
+                        dir: dir.as_path(),
+                        files: directory.files.len(),
+                    });
                     for file in directory.files {
                         self.execute_file(file, true)?;
                     }
                     for dir in directory.subdirs {
-                        self.execute_directory(dir, self.config.recursive);
+                        self.execute_directory(dir, self.config.recursive, scope.clone());
                     }
                 }
-                TaskResult::Preprocess(result) => {
-                    let preprocess_result = result.map_err(|e| {
-                        self.progress.add_done_quiet(1);
-                        e.change_context(TxtppError)
-                    })?;
+                TaskResult::Preprocess(input, result, duration) => {
+                    *self.durations.entry(input.clone()).or_default() += duration;
+                    let preprocess_result = match result {
+                        Ok(r) => r,
+                        Err(e) => {
+                            if let Some(diff) = e.downcast_ref::<VerifyDiff>() {
+                                self.progress.notify(&Event::Diff { diff });
+                                self.diffs.push(diff.clone());
+                            }
+                            if !self.config.keep_going {
+                                return Err(e.change_context(TxtppError::default()));
+                            }
+                            self.fail_file(&input, e, &mut dep_mgr)?;
+                            continue;
+                        }
+                    };
+                    let status = match &preprocess_result {
+                        PpResult::Unchanged(..) => FileStatus::Unchanged,
+                        PpResult::Fresh(..) => FileStatus::Fresh,
+                        _ => match self.config.mode {
+                            Mode::Build | Mode::InMemoryBuild => FileStatus::Written,
+                            Mode::Clean => FileStatus::Cleaned,
+                            Mode::Verify => FileStatus::Verified,
+                            Mode::Plan => FileStatus::Planned,
+                        },
+                    };
                     match preprocess_result {
                         PpResult::HasDeps(input, deps) => {
                             log::info!("file {input} has dependencies: {deps:?}");
-                            if dep_mgr.add_dependency(&input, &deps) {
+                            if deps.iter().any(|dep| dep_mgr.is_failed(dep)) {
+                                // a dependency already failed, so this file can't be processed
+                                let mut skipped = dep_mgr.notify_fail(&input);
+                                skipped.insert(input);
+                                self.skip_files(skipped)?;
+                            } else if dep_mgr.add_dependency(&input, &deps) {
                                 // schedule the dependencies
                                 for dep in deps {
                                     self.execute_file(dep, true)?;
@@ -188,19 +367,45 @@ impl Txtpp {
                                 self.execute_file(input, false)?;
                             }
                         }
-                        PpResult::Ok(input) => {
+                        PpResult::Ok(input, record) | PpResult::Unchanged(input, record) => {
                             log::info!("file {input} done");
-                            let file_target = input.trim_txtpp().map_err(|e| {
-                                e.change_context(TxtppError)
-                                    .attach_printable("cannot trim txtpp extension")
-                            })?;
-                            let _ = self.progress.print_status(
-                                self.config.mode.processed_verb(),
-                                &file_target,
-                                Color::Green,
-                                false,
-                            );
-                            file_count += 1;
+                            if let Some(cache) = &mut self.new_cache {
+                                cache.record(&input, &record).map_err(|e| {
+                                    e.change_context(TxtppError::default())
+                                        .attach_printable("cannot update build cache")
+                                })?;
+                            }
+                            let plan_deps = if let Mode::Plan = self.config.mode {
+                                // dependencies are not built in plan mode, but they are part of the plan
+                                record
+                                    .deps
+                                    .iter()
+                                    .filter_map(|dep| self.opts.out_dir.source_of(dep, input.fs()))
+                                    .map(|dep| input.share_base(dep))
+                                    .collect::<Result<Vec<_>, _>>()
+                                    .map_err(|e| {
+                                        e.change_context(TxtppError::default())
+                                            .attach_printable("cannot resolve dependency")
+                                    })?
+                            } else {
+                                vec![]
+                            };
+                            self.update_depfile(base_abs_path.as_path(), &input, &record)?;
+                            self.add_report(&input, status, &record)?;
+                            self.index.update(&input, record);
+                            for dep in plan_deps {
+                                self.execute_file(dep, true)?;
+                            }
+                            let files = dep_mgr.notify_finish(&input);
+                            for file in files {
+                                self.execute_file(file, false)?;
+                            }
+                        }
+                        PpResult::Fresh(input, record) => {
+                            log::info!("file {input} is up-to-date");
+                            self.update_depfile(base_abs_path.as_path(), &input, &record)?;
+                            self.add_report(&input, status, &record)?;
+                            self.index.update(&input, record);
                             let files = dep_mgr.notify_finish(&input);
                             for file in files {
                                 self.execute_file(file, false)?;
@@ -211,43 +416,292 @@ impl Txtpp {
             }
         }
 
+        if let Some(errors) = self.errors.take() {
+            let summary = self
+                .failures
+                .iter()
+                .map(|failure| format!("failed: `{}`", failure.error.file))
+                .chain(self.skipped.iter().map(|file| format!("skipped: `{file}`")))
+                .collect::<Vec<_>>();
+            let error = TxtppError {
+                failures: std::mem::take(&mut self.failures),
+                skipped: std::mem::take(&mut self.skipped),
+            };
+            let mut report = errors.change_context(error);
+            for line in summary {
+                report = report.attach_printable(line);
+            }
+            return Err(report);
+        }
+
         // make sure all dependencies are processed
         let remaining = dep_mgr.take_remaining();
         if !remaining.is_empty() {
-            return Err(Report::new(TxtppError)
+            return Err(Report::new(TxtppError::default())
                 .attach_printable("Circular dependencies are found:")
-                .attach_printable(print_dep_map(&remaining)));
+                .attach_printable(
+                    DepGraph::from_dep_map(&remaining, base_abs_path.as_path(), &self.opts.out_dir)
+                        .to_dot(),
+                ));
+        }
+
+        if let Mode::Plan = self.config.mode {
+            let graph = DepGraph::new(&self.index, base_abs_path.as_path(), &self.opts.out_dir);
+            let plan = match self.config.graph_format {
+                None => graph.to_plan(),
+                Some(GraphFormat::Dot) => graph.to_dot(),
+                Some(GraphFormat::Json) => format!("{}\n", graph.to_json()),
+            };
+            if graph.build_order().is_err() {
+                return Err(Report::new(TxtppError::default())
+                    .attach_printable("Circular dependencies are found in the plan")
+                    .attach_printable(plan));
+            }
+            self.report.plan = Some(plan);
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the tasks still running after an error, and record the outputs they wrote
+    ///
+    /// Otherwise, the outputs written after the cache is saved would be seen as changed by hand in the next run.
+    fn finish_tasks(&mut self) {
+        self.threadpool.join();
+        let cache = match &mut self.new_cache {
+            Some(cache) => cache,
+            None => return,
+        };
+        while let Ok(data) = self.recv.try_recv() {
+            if let TaskResult::Preprocess(
+                _,
+                Ok(PpResult::Ok(input, record) | PpResult::Unchanged(input, record)),
+                _,
+            ) = data
+            {
+                if let Err(e) = cache.record(&input, &record) {
+                    log::debug!("{e:?}");
+                    self.progress.notify(&Event::Warning {
+                        message: &format!("cannot update build cache for `{input}`"),
+                    });
+                }
+            }
         }
+    }
 
-        let _ = self.progress.print_status(
-            verbs::SCANNED,
-            &format!("{} path(s)", self.progress.total_count),
-            Color::Yellow,
-            true,
-        );
+    /// Add the file to the report and notify the observer
+    fn add_report(
+        &mut self,
+        input: &AbsPath,
+        status: FileStatus,
+        record: &FileRecord,
+    ) -> Result<(), TxtppError> {
+        let output = self.opts.out_dir.output_of(input.as_path()).map_err(|e| {
+            e.change_context(TxtppError::default())
+                .attach_printable("cannot resolve output path")
+        })?;
+        let duration = match status {
+            FileStatus::Fresh => Duration::ZERO,
+            _ => self.durations.remove(input).unwrap_or_default(),
+        };
+        let file = FileReport {
+            input: input.as_path_buf().clone(),
+            output,
+            status,
+            duration,
+            deps: record.deps.clone(),
+            temps: record.temps.clone(),
+        };
+        self.progress.notify(&Event::FileFinished { file: &file });
+        self.report.files.push(file);
+        Ok(())
+    }
 
-        let elapsed = start_time.elapsed();
+    /// Write the depfile for the output of the txtpp file, or remove it when cleaning
+    fn update_depfile(
+        &self,
+        base: &Path,
+        input: &AbsPath,
+        record: &FileRecord,
+    ) -> Result<(), TxtppError> {
+        if !self.config.depfile {
+            return Ok(());
+        }
+        let output = self.opts.out_dir.output_of(input.as_path()).map_err(|e| {
+            e.change_context(TxtppError::default())
+                .attach_printable("cannot resolve output path")
+        })?;
+        let mut depfile = output.clone().into_os_string();
+        depfile.push(DEPFILE_EXT);
+        let depfile = PathBuf::from(depfile);
+        match self.config.mode {
+            Mode::Build | Mode::InMemoryBuild => {
+                let target = relative_to(base, &output);
+                let mut seen = HashSet::new();
+                let prerequisites = std::iter::once(input.as_path())
+                    .chain(record.deps.iter().map(PathBuf::as_path))
+                    .filter(|p| seen.insert(*p))
+                    .map(|p| relative_to(base, p))
+                    .collect::<Vec<_>>();
+                let content = make_depfile(&target, prerequisites.iter().map(String::as_str));
+                if input.fs().read_to_string(&depfile).ok().as_ref() == Some(&content) {
+                    // don't touch the depfile if it's the same
+                    return Ok(());
+                }
+                input.fs().write(&depfile, content.as_bytes()).map_err(|e| {
+                    Report::new(e)
+                        .change_context(TxtppError::default())
+                        .attach_printable(format!("cannot write depfile: `{}`", depfile.display()))
+                })
+            }
+            Mode::Clean if input.fs().exists(&depfile) => {
+                input.fs().remove_file(&depfile).map_err(|e| {
+                    Report::new(e)
+                        .change_context(TxtppError::default())
+                        .attach_printable(format!("cannot remove depfile: `{}`", depfile.display()))
+                })
+            }
+            _ => Ok(()),
+        }
+    }
 
-        let _ = self.progress.print_status(
-            verbs::DONE,
-            &format!("{file_count} file(s) in {:.2}s", elapsed.as_secs_f32()),
-            Color::Green,
-            false,
-        );
+    /// Record a file that failed when running with `keep_going`, and skip the files depending on it
+    fn fail_file(
+        &mut self,
+        input: &AbsPath,
+        error: Report<PpError>,
+        dep_mgr: &mut DepManager,
+    ) -> Result<(), TxtppError> {
+        log::info!("file {input} failed");
+        self.progress.notify(&Event::FileFailed {
+            input: input.as_path(),
+            error: &error,
+        });
+        self.failures.push(Failure::from(&error));
+        match &mut self.errors {
+            Some(errors) => errors.extend_one(error),
+            None => self.errors = Some(error),
+        }
+        self.skip_files(dep_mgr.notify_fail(input))
+    }
 
+    /// Record files that are skipped because of a failed dependency
+    fn skip_files(&mut self, files: HashSet<AbsPath>) -> Result<(), TxtppError> {
+        let mut files = files.into_iter().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.as_path().cmp(b.as_path()));
+        for file in files {
+            log::info!("file {file} skipped");
+            self.progress.notify(&Event::FileSkipped {
+                input: file.as_path(),
+            });
+            self.skipped.push(file.to_string());
+        }
         Ok(())
     }
 
-    fn execute_directory(&mut self, dir: AbsPath, recursive: bool) {
-        let _ = self
-            .progress
-            .print_status(verbs::SCANNING, &dir.to_string(), Color::Yellow, true);
-        let send = self.send.clone();
+    /// Get the options for the file, with the settings from the nested config files in the directories above it
+    fn opts_for(&mut self, file: &AbsPath) -> Result<Arc<PpOpts>, TxtppError> {
+        if !self.config.config_files {
+            return Ok(Arc::clone(&self.opts));
+        }
+        let relative = match file
+            .as_path()
+            .parent()
+            .and_then(|dir| dir.strip_prefix(self.base.as_path()).ok())
+        {
+            Some(relative) => relative,
+            None => return Ok(Arc::clone(&self.opts)),
+        };
+        let mut dirs = vec![];
+        let mut current = self.base.as_path_buf().clone();
+        for component in relative.components() {
+            current.push(component);
+            if !self.nested_files.contains_key(&current) {
+                let path = current.join(CONFIG_FILE);
+                let nested = if self.base.fs().is_file(&path) {
+                    log::info!("using nested config file: {}", path.display());
+                    let nested = ConfigFile::load(self.base.fs(), &path).map_err(|e| {
+                        e.change_context(TxtppError::default())
+                            .attach_printable("cannot load nested config file")
+                    })?;
+                    Some(nested)
+                } else {
+                    None
+                };
+                self.nested_files.insert(current.clone(), nested);
+            }
+            if self.nested_files[&current].is_some() {
+                dirs.push(current.clone());
+            }
+        }
+        let dir = match dirs.last() {
+            Some(dir) => dir,
+            None => return Ok(Arc::clone(&self.opts)),
+        };
+        if let Some(opts) = self.dir_opts.get(dir) {
+            return Ok(Arc::clone(opts));
+        }
+        let mut config = self.config.clone();
+        for nested in dirs
+            .iter()
+            .filter_map(|dir| self.nested_files[dir].as_ref())
+        {
+            nested.per_file().apply_to(&mut config);
+        }
+        // the overrides still take precedence
+        self.config.overrides.per_file().apply_to(&mut config);
+        let opts = Arc::new(make_opts(
+            &config,
+            self.opts.out_dir.clone(),
+            &self.run_pool,
+        )?);
+        self.dir_opts.insert(dir.clone(), Arc::clone(&opts));
+        Ok(opts)
+    }
+
+    /// If the file should be processed regardless of the cache
+    fn is_always_dirty(&self, file: &AbsPath) -> bool {
+        self.always_dirty.files.contains(file)
+            || self
+                .always_dirty
+                .subdirs
+                .iter()
+                .any(|dir| file.as_path().starts_with(dir))
+    }
+
+    fn execute_directory(&mut self, dir: AbsPath, recursive: bool, scope: ScanScope) {
+        // the same directory can be reached again through symbolic links
+        if !self.dirs.insert(dir.clone()) {
+            return;
+        }
+        self.progress.add_total(1);
+        let filter = Arc::clone(&self.filter);
         log::info!("scanning directory: {dir}");
+        self.dispatch(move || {
+            let result = scan_dir(&dir, recursive, &filter, &scope);
+            TaskResult::ScanDir(dir, result)
+        });
+    }
+
+    /// Run the task in the thread pool and send its result to the main thread
+    fn dispatch<F>(&mut self, task: F)
+    where
+        F: FnOnce() -> TaskResult + Send + 'static,
+    {
+        self.pending += 1;
+        let send = self.send.clone();
         self.threadpool.execute(move || {
-            let result = scan_dir(&dir, recursive);
-            send.send(TaskResult::ScanDir(result))
-                .expect("cannot send result")
+            // a panicking task must still send a result, or the main thread would wait forever
+            let result = panic::catch_unwind(AssertUnwindSafe(task)).unwrap_or_else(|e| {
+                let message = e
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                TaskResult::Panicked(message)
+            });
+            // the receiver is gone if the run already stopped because of an error
+            let _ = send.send(result);
         });
     }
 
@@ -260,62 +714,100 @@ impl Txtpp {
             if !self.files.insert(file.clone()) {
                 return Ok(());
             }
+            if self.up_to_date.contains(&file) {
+                self.progress.add_total(1);
+                self.pending += 1;
+                let record = self.index.get(&file).cloned().unwrap_or_default();
+                self.send
+                    .send(TaskResult::Preprocess(
+                        file.clone(),
+                        Ok(PpResult::Fresh(file, record)),
+                        Duration::ZERO,
+                    ))
+                    .expect("cannot send result");
+                return Ok(());
+            }
         }
 
-        let _ = self.progress.add_total(1);
-        let file_target = file.trim_txtpp().map_err(|e| {
-            e.change_context(TxtppError)
-                .attach_printable("cannot trim txtpp extension")
+        self.progress.add_total(1);
+        let output = self.opts.out_dir.output_of(file.as_path()).map_err(|e| {
+            e.change_context(TxtppError::default())
+                .attach_printable("cannot resolve output path")
         })?;
-        let _ = self.progress.print_status(
-            self.config.mode.processing_verb(),
-            &file_target,
-            Color::Yellow,
-            true,
-        );
-        let send = self.send.clone();
-        let shell = self.shell.clone();
-        let mode = self.config.mode.clone();
-        let trailing_newline = self.config.trailing_newline;
+        self.progress.notify(&Event::FileStarted {
+            input: file.as_path(),
+            output: &output,
+        });
+        let opts = self.opts_for(&file)?;
+        let last_output = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.output_hash(&file));
+        let cache = if !self.config.incremental || self.is_always_dirty(&file) {
+            None
+        } else {
+            self.cache.clone()
+        };
         log::info!("processing file: {file}");
-        self.threadpool.execute(move || {
-            let result = preprocess(&shell, &file, mode, is_first_pass, trailing_newline);
-            send.send(TaskResult::Preprocess(result))
-                .expect("cannot send result")
+        self.dispatch(move || {
+            let start_time = Instant::now();
+            let result = preprocess(&opts, &file, is_first_pass, cache.as_deref(), last_output);
+            TaskResult::Preprocess(file, result, start_time.elapsed())
         });
         Ok(())
     }
 }
 
+/// Make the options for preprocessing from the config
+fn make_opts(
+    config: &Config,
+    out_dir: OutDir,
+    run_pool: &ThreadPool,
+) -> Result<PpOpts, TxtppError> {
+    let shell = Shell::new(&config.shell_cmd)
+        .map_err(|e| {
+            e.change_context(TxtppError::default())
+                .attach_printable(format!(
+                    "cannot parse shell command: {cmd}",
+                    cmd = config.shell_cmd
+                ))
+        })?
+        .with_env(config.env.clone());
+    Ok(PpOpts {
+        shell,
+        mode: config.mode.clone(),
+        trailing_newline: config.trailing_newline,
+        verify_diff: config.verify_diff,
+        out_dir,
+        observer: config.observer.clone(),
+        symlinks: config.symlinks,
+        run_pool: config.parallel_run.then(|| run_pool.clone()),
+        source_map: config.source_map,
+        line_markers: config
+            .line_markers
+            .iter()
+            .map(|(ext, format)| (ext.clone(), LineMarker::new(format)))
+            .collect(),
+        banner: config.banner.clone(),
+    })
+}
+
 impl Drop for Txtpp {
     fn drop(&mut self) {
         log::info!("cleaning up txtpp");
+        // wait for the running tasks, so they are not stopped in the middle of writing files.
+        // The results of tasks still running after an error are discarded with the channel
         self.threadpool.join();
-        // wait for all workers to finish sending their last results, which we will ignore
-        loop {
-            match self.recv.try_recv() {
-                Ok(_) => {
-                    self.progress.add_done_quiet(1);
-                }
-                Err(TryRecvError::Empty) => {
-                    if self.progress.is_done() || self.progress.has_error {
-                        break;
-                    }
-                    // no data available, wait for a bit
-                    std::thread::sleep(std::time::Duration::from_millis(100));
-                    continue;
-                }
-                Err(TryRecvError::Disconnected) => {
-                    break;
-                }
-            }
-        }
+        self.run_pool.join();
         log::info!("txtpp destroyed");
-        // the channel will be dropped
     }
 }
 
 enum TaskResult {
-    ScanDir(Result<Directory, PathError>),
-    Preprocess(Result<PpResult, PpError>),
+    /// The result of scanning the directory
+    ScanDir(AbsPath, Result<(Directory, ScanScope), PathError>),
+    /// The result of preprocessing the file and the time it took
+    Preprocess(AbsPath, Result<PpResult, PpError>, Duration),
+    /// The task panicked, with the panic message
+    Panicked(String),
 }
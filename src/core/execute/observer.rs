@@ -0,0 +1,143 @@
+use super::{Config, FileReport, Mode, RunReport};
+use crate::core::TerminalObserver;
+use crate::error::{PpError, TxtppError, VerifyDiff};
+use error_stack::Report;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Receiver of [`Event`]s during a run, set through [`Config::observer`]
+///
+/// Use this to render the progress in your own way. The default is [`TerminalObserver`],
+/// which prints colored status lines to stderr.
+///
+/// Events about directives are sent from the worker threads, and the other events are sent from the thread
+/// calling [`Txtpp::run`](super::Txtpp::run).
+///
+/// # Example
+/// ```no_run
+/// use std::sync::Arc;
+/// use txtpp::{Config, Event, Observer, Txtpp};
+///
+/// #[derive(Debug)]
+/// struct Printer;
+///
+/// impl Observer for Printer {
+///     fn notify(&self, event: &Event) {
+///         if let Event::FileFinished { file } = event {
+///             println!("{}: {:?}", file.input.display(), file.status);
+///         }
+///     }
+/// }
+///
+/// let config = Config {
+///     observer: Some(Arc::new(Printer)),
+///     ..Default::default()
+/// };
+/// Txtpp::run(config).unwrap();
+/// ```
+pub trait Observer: fmt::Debug + Send + Sync {
+    /// Called for every event
+    fn notify(&self, event: &Event);
+}
+
+/// An event during a run
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// The run is started
+    Started {
+        /// The absolute base directory
+        base: &'a Path,
+        /// The mode
+        mode: &'a Mode,
+        /// The shell for `run` directives
+        shell: &'a str,
+        /// The number of worker threads
+        threads: usize,
+    },
+    /// A directory is scanned for `.txtpp` files
+    DirScanned {
+        /// The absolute path of the directory
+        dir: &'a Path,
+        /// The number of `.txtpp` files found
+        files: usize,
+    },
+    /// A `.txtpp` file is being processed.
+    ///
+    /// This can happen more than once for a file, if it needs to wait for its dependencies
+    FileStarted {
+        /// The absolute path of the `.txtpp` file
+        input: &'a Path,
+        /// The absolute path of the output file
+        output: &'a Path,
+    },
+    /// A directive was executed
+    DirectiveExecuted {
+        /// The file being processed, as shown in errors
+        file: &'a str,
+        /// The line number of the directive
+        line: usize,
+        /// The directive
+        directive: &'a str,
+    },
+    /// A `.txtpp` file is done
+    FileFinished {
+        /// What was done to the file
+        file: &'a FileReport,
+    },
+    /// A `.txtpp` file failed when running with [`Config::keep_going`]
+    FileFailed {
+        /// The absolute path of the `.txtpp` file
+        input: &'a Path,
+        /// The error
+        error: &'a Report<PpError>,
+    },
+    /// A `.txtpp` file is skipped because it depends on a failed file
+    FileSkipped {
+        /// The absolute path of the `.txtpp` file
+        input: &'a Path,
+    },
+    /// An output is different from the fresh output when verifying with [`Config::verify_diff`]
+    Diff {
+        /// The diff
+        diff: &'a VerifyDiff,
+    },
+    /// The number of tasks done and scheduled changed
+    Progress {
+        /// Tasks done
+        done: usize,
+        /// Tasks scheduled
+        total: usize,
+    },
+    /// Something is wrong, but the run can continue
+    Warning {
+        /// The warning
+        message: &'a str,
+    },
+    /// The run failed
+    Error {
+        /// The error
+        error: &'a Report<TxtppError>,
+    },
+    /// The run is done
+    Finished {
+        /// The report of the run
+        report: &'a RunReport,
+        /// The number of paths scanned, including directories
+        paths: usize,
+    },
+    /// Watching for changes after a run with [`watch`](super::watch)
+    Watching {
+        /// The number of paths watched
+        paths: usize,
+    },
+}
+
+/// Get the observer from the config, which is the terminal printer if not set
+pub(crate) fn observer_of(config: &Config) -> Arc<dyn Observer> {
+    match &config.observer {
+        Some(observer) => Arc::clone(observer),
+        None => Arc::new(TerminalObserver::new(config.verbosity.clone())),
+    }
+}
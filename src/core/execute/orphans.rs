@@ -0,0 +1,106 @@
+use super::cache::{BuildCache, Orphan};
+use super::pp::SOURCE_MAP_EXT;
+use super::{Event, FileReport, FileStatus, Txtpp, DEPFILE_EXT};
+use crate::core::relative_to;
+use crate::error::TxtppError;
+use crate::fs::hash_file;
+use error_stack::{Report, Result, ResultExt};
+use std::path::PathBuf;
+use std::time::Duration;
+
+impl Txtpp {
+    /// Remove the files produced by the `.txtpp` files that were deleted, according to the cache.
+    ///
+    /// The files are added to [`RunReport::orphan_files`](super::RunReport::orphan_files).
+    /// With [`Config::dry_run`](super::Config::dry_run), they are not removed.
+    pub(super) fn clean_orphans(&mut self) -> Result<(), TxtppError> {
+        let mut cache = BuildCache::load(&self.base, &self.opts.out_dir, &self.progress);
+        let orphans = cache.orphans().map_err(|e| {
+            e.change_context(TxtppError::default())
+                .attach_printable("cannot read orphans from the build cache")
+        })?;
+        if !self.config.force && !self.config.dry_run {
+            // check everything first, so nothing is removed if one of them fails
+            for orphan in orphans.iter().filter(|orphan| !orphan.shared_output) {
+                self.check_orphan_output(orphan)?;
+            }
+        }
+        for orphan in orphans {
+            self.progress.add_total(1);
+            self.progress.notify(&Event::FileStarted {
+                input: &orphan.source,
+                output: &orphan.output,
+            });
+            let files = self.orphan_files(&orphan);
+            let status = if self.config.dry_run {
+                FileStatus::Planned
+            } else {
+                for file in &files {
+                    self.base.fs().remove_file(file).map_err(|e| {
+                        Report::new(e)
+                            .change_context(TxtppError::default())
+                            .attach_printable(format!(
+                                "cannot remove orphaned file: `{}`",
+                                file.display()
+                            ))
+                    })?;
+                }
+                cache.remove(&orphan.source);
+                FileStatus::Cleaned
+            };
+            self.report.orphan_files.extend(files);
+            let file = FileReport {
+                input: orphan.source,
+                output: orphan.output,
+                status,
+                duration: Duration::ZERO,
+                deps: vec![],
+                temps: orphan.temps,
+            };
+            self.progress.notify(&Event::FileFinished { file: &file });
+            self.report.files.push(file);
+            self.progress.add_done(1);
+        }
+        if self.config.dry_run {
+            return Ok(());
+        }
+        cache.save().map_err(|e| {
+            e.change_context(TxtppError::default())
+                .attach_printable("cannot save build cache")
+        })
+    }
+
+    /// Check that the output of the orphan was not changed since it was generated
+    fn check_orphan_output(&self, orphan: &Orphan) -> Result<(), TxtppError> {
+        let hash =
+            hash_file(self.base.fs(), &orphan.output).change_context(TxtppError::default())?;
+        if hash.is_none() || hash == orphan.output_hash {
+            return Ok(());
+        }
+        Err(Report::new(TxtppError::default()).attach_printable(format!(
+            "`{}` was changed since it was generated. Use --force to remove it",
+            relative_to(self.base.as_path(), &orphan.output)
+        )))
+    }
+
+    /// Get the existing files to remove for the orphan
+    fn orphan_files(&self, orphan: &Orphan) -> Vec<PathBuf> {
+        let mut files = vec![];
+        if !orphan.shared_output {
+            files.push(orphan.output.clone());
+            for (enabled, ext) in [
+                (self.config.depfile, DEPFILE_EXT),
+                (self.config.source_map, SOURCE_MAP_EXT),
+            ] {
+                if enabled {
+                    let mut path = orphan.output.clone().into_os_string();
+                    path.push(ext);
+                    files.push(PathBuf::from(path));
+                }
+            }
+        }
+        files.extend(orphan.temps.iter().cloned());
+        files.retain(|file| self.base.fs().is_file(file));
+        files
+    }
+}
@@ -276,6 +276,20 @@ mod ut {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_from_basic_run_seq() {
+        let line = "TXTPP#run-seq hello";
+        let expected = Some(Directive::new(
+            "",
+            "",
+            DirectiveType::RunSeq,
+            vec!["hello".to_string()],
+        ));
+        let actual = Directive::detect_from(line);
+
+        assert_eq!(expected, actual);
+    }
+
     #[test]
     fn test_from_starting_whitespaces() {
         let line = "  \t  \t TXTPP#include hello ";
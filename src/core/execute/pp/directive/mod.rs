@@ -64,6 +64,8 @@ pub enum DirectiveType {
     After,
     /// Run directive, argument is a command
     Run,
+    /// Run directive that is never run concurrently with other commands, argument is a command
+    RunSeq,
     /// Tag directive, argument is a string
     Tag,
     /// Temp directive, argument is file content
@@ -80,6 +82,7 @@ impl TryFrom<&str> for DirectiveType {
             "" => Ok(DirectiveType::Empty),
             "include" => Ok(DirectiveType::Include),
             "run" => Ok(DirectiveType::Run),
+            "run-seq" => Ok(DirectiveType::RunSeq),
             "tag" => Ok(DirectiveType::Tag),
             "temp" => Ok(DirectiveType::Temp),
             "write" => Ok(DirectiveType::Write),
@@ -106,6 +109,7 @@ impl Display for DirectiveType {
             DirectiveType::Include => write!(f, "include"),
             DirectiveType::After => write!(f, "after"),
             DirectiveType::Run => write!(f, "run"),
+            DirectiveType::RunSeq => write!(f, "run-seq"),
             DirectiveType::Tag => write!(f, "tag"),
             DirectiveType::Temp => write!(f, "temp"),
             DirectiveType::Write => write!(f, "write"),
@@ -1,50 +1,197 @@
-use crate::core::{Mode, TagState};
+use super::cache::{BuildCache, CacheCheck};
+use crate::core::{
+    make_banner, Event, FileRecord, LineMarker, Mode, Observer, Origin, SourceMapBuilder,
+    SymlinkPolicy, TagState,
+};
 use crate::error::{PpError, PpErrorKind};
-use crate::fs::{AbsPath, IOCtx, Shell, TxtppPath};
+use crate::fs::{clean_path, write_atomic, AbsPath, IOCtx, OutDir, Shell, ShellError, TxtppPath};
 use error_stack::{Report, Result, ResultExt};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use threadpool::ThreadPool;
 
 mod directive;
 pub use directive::*;
 
+/// Extension appended to the output file name for its source map
+pub(super) const SOURCE_MAP_EXT: &str = ".map";
+
+/// Options for preprocessing, shared by all files in a run
+#[derive(Debug)]
+pub struct PpOpts {
+    /// The shell to execute `run` directives
+    pub shell: Shell,
+    /// The mode
+    pub mode: Mode,
+    /// If the output files should have trailing newline
+    pub trailing_newline: bool,
+    /// If the diff should be attached to verify errors
+    pub verify_diff: bool,
+    /// Where the outputs are
+    pub out_dir: OutDir,
+    /// Receiver of the directive events
+    pub observer: Option<Arc<dyn Observer>>,
+    /// How to treat symbolic links as `include` and `after` targets
+    pub symlinks: SymlinkPolicy,
+    /// Pool to run `run` directives in the background until the next directive that depends on them.
+    ///
+    /// It's shared by all files, so the number of commands running at once is limited by its size.
+    /// `None` if the commands are not run in the background
+    pub run_pool: Option<ThreadPool>,
+    /// If a source map is written next to each output
+    pub source_map: bool,
+    /// Line markers to write, by the extension of the output file
+    pub line_markers: HashMap<String, LineMarker>,
+    /// Text of the banner added to the start of the outputs
+    pub banner: Option<String>,
+}
+
 /// Preprocess the txtpp file
+///
+/// If a cache is provided, the file is skipped when it is up-to-date according to the cache.
+/// `last_output` is the hash of the output from the last run, so the output is not overwritten if it was changed since then.
 pub fn preprocess(
-    shell: &Shell,
+    opts: &PpOpts,
     input_file: &AbsPath,
-    mode: Mode,
     is_first_pass: bool,
-    trailing_newline: bool,
+    cache: Option<&BuildCache>,
+    last_output: Option<String>,
 ) -> Result<PpResult, PpError> {
-    Pp::run(input_file, shell, mode, is_first_pass, trailing_newline)
+    if let Some(cache) = cache {
+        match cache.check(input_file, is_first_pass) {
+            CacheCheck::Fresh(record) => return Ok(PpResult::Fresh(input_file.clone(), record)),
+            CacheCheck::Deps(deps) => return Ok(PpResult::HasDeps(input_file.clone(), deps)),
+            CacheCheck::Dirty => {}
+        }
+    }
+    Pp::run(input_file, opts, is_first_pass, last_output)
+}
+
+/// Preprocess a txtpp source from a stream, and write the output to another stream
+///
+/// No output file is involved, so dependencies are not built before the source is processed.
+/// Files in `include` directives are read as they are on disk.
+pub fn preprocess_stream(
+    opts: &PpOpts,
+    input: Box<dyn BufRead>,
+    name: &str,
+    work_dir: &AbsPath,
+    output: Box<dyn Write>,
+) -> Result<FileRecord, PpError> {
+    let context = IOCtx::from_stream(input, name, work_dir.clone(), output)?;
+    match Pp::new(context, opts, false).run_internal(opts.trailing_newline)? {
+        PpOutcome::Done { record, .. } => Ok(record),
+        PpOutcome::HasDeps(_) => unreachable!("dependencies are never collected in execute mode"),
+    }
 }
 
 /// Preprocesser runtime
 struct Pp<'a> {
     shell: &'a Shell,
-    input_file: AbsPath,
+    out_dir: &'a OutDir,
+    observer: Option<&'a dyn Observer>,
+    symlinks: SymlinkPolicy,
+    run_pool: Option<&'a ThreadPool>,
     mode: Mode,
     context: IOCtx,
+    /// Output waiting for the commands running in the background, in source order
+    queued: Vec<Chunk>,
+    /// Where the lines in the output are from, if the source map is written
+    source_map: Option<SourceMapBuilder>,
+    /// Format of the line markers to write, if any
+    line_marker: Option<&'a LineMarker>,
+    /// The origin that continues the last output without a line marker
+    next_origin: Option<Origin>,
+    /// If the output is at the start of a line
+    at_line_start: bool,
+    /// The banner that is not written yet
+    banner: Option<String>,
+    /// If nothing is written to the output yet
+    at_file_start: bool,
     cur_directive: Option<Directive>,
+    /// Line number where the current directive starts
+    directive_line: usize,
     tag_state: TagState,
     pp_mode: PpMode,
     execute_tail_line: Option<String>,
+    record: FileRecord,
 }
 
 impl<'a> Pp<'a> {
     fn run(
         input_file: &AbsPath,
-        shell: &'a Shell,
-        mode: Mode,
+        opts: &'a PpOpts,
         is_first_pass: bool,
-        trailing_newline: bool,
+        last_output: Option<String>,
     ) -> Result<PpResult, PpError> {
-        let context = IOCtx::new(input_file, mode.clone())?;
+        let output_file = opts.out_dir.output_of(input_file.as_path()).map_err(|e| {
+            e.change_context(PpError {
+                kind: PpErrorKind::OpenFile,
+                file: input_file.to_string(),
+                line: 0,
+            })
+            .attach_printable(format!(
+                "could not resolve output path for input file: `{input_file}`"
+            ))
+        })?;
+        let ext = output_file
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default();
+        let line_marker = opts.line_markers.get(ext);
+        let mut context = IOCtx::new(
+            input_file,
+            output_file.clone(),
+            opts.mode.clone(),
+            opts.verify_diff,
+            line_marker,
+        )?;
+        context.set_last_output(last_output);
+        let mut pp = Self::new(context, opts, is_first_pass);
+        pp.line_marker = line_marker;
+        pp.banner = opts.banner.as_ref().and_then(|text| {
+            make_banner(ext, text, &input_file.to_string(), pp.context.line_ending)
+        });
+        let result = match pp.run_internal(opts.trailing_newline)? {
+            PpOutcome::Done {
+                record,
+                written,
+                source_map,
+            } => {
+                update_source_map(opts, input_file, &output_file, source_map)?;
+                if !written && opts.mode == Mode::InMemoryBuild {
+                    PpResult::Unchanged(input_file.clone(), record)
+                } else {
+                    PpResult::Ok(input_file.clone(), record)
+                }
+            }
+            PpOutcome::HasDeps(deps) => PpResult::HasDeps(input_file.clone(), deps),
+        };
+        Ok(result)
+    }
+
+    fn new(context: IOCtx, opts: &'a PpOpts, is_first_pass: bool) -> Self {
         Self {
-            shell,
-            input_file: input_file.clone(),
-            mode,
+            shell: &opts.shell,
+            out_dir: &opts.out_dir,
+            observer: opts.observer.as_deref(),
+            symlinks: opts.symlinks,
+            run_pool: opts.run_pool.as_ref(),
+            mode: opts.mode.clone(),
             context,
+            queued: vec![],
+            source_map: (opts.source_map && matches!(opts.mode, Mode::Build | Mode::InMemoryBuild))
+                .then(SourceMapBuilder::new),
+            line_marker: None,
+            next_origin: None,
+            at_line_start: true,
+            banner: None,
+            at_file_start: true,
             cur_directive: None,
+            directive_line: 0,
             tag_state: TagState::new(),
             pp_mode: if is_first_pass {
                 PpMode::FirstPassExecute
@@ -52,11 +199,11 @@ impl<'a> Pp<'a> {
                 PpMode::Execute
             },
             execute_tail_line: None,
+            record: FileRecord::default(),
         }
-        .run_internal(trailing_newline)
     }
 
-    fn run_internal(mut self, trailing_newline: bool) -> Result<PpResult, PpError> {
+    fn run_internal(mut self, trailing_newline: bool) -> Result<PpOutcome, PpError> {
         let mut add_newline_before_next_output = false;
         // read txtpp file line by line
         loop {
@@ -78,27 +225,43 @@ impl<'a> Pp<'a> {
                     } else {
                         line
                     };
-                    (Some(line), false)
+                    let origin = Origin::Source {
+                        line: self.context.cur_line,
+                    };
+                    (Some(Chunk::Text(line, Some(origin))), false)
                 }
                 IterDirectiveResult::Execute(d, line) => {
-                    let whitespaces = d.whitespaces.clone();
-                    let d_str = format!("for `{d}`");
-                    let directive_output = if let Some(raw_output) = self
-                        .execute_directive(d)
-                        .map_err(|e| e.attach_printable(d_str))?
-                    {
-                        log::debug!("directive output: {raw_output:?}");
-                        if self.tag_state.try_store(&raw_output).is_err() {
-                            Some(self.format_directive_output(
-                                &whitespaces,
-                                raw_output.lines(),
-                                raw_output.ends_with('\n'),
-                            ))
+                    let run_pool = self.run_pool.filter(|_| self.can_run_in_background(&d));
+                    let directive_output = if let Some(run_pool) = run_pool {
+                        Some(Chunk::Run(self.spawn_run(run_pool, d)))
+                    } else {
+                        if !matches!(
+                            d.directive_type,
+                            DirectiveType::Empty | DirectiveType::Write
+                        ) {
+                            // the directive may depend on the commands running in the background
+                            self.flush_queued()?;
+                        }
+                        let whitespaces = d.whitespaces.clone();
+                        let d_str = format!("for `{d}`");
+                        if let Some((raw_output, origin)) = self
+                            .execute_directive(d)
+                            .map_err(|e| e.attach_printable(d_str))?
+                        {
+                            log::debug!("directive output: {raw_output:?}");
+                            if self.tag_state.try_store(&raw_output).is_err() {
+                                let output = self.format_directive_output(
+                                    &whitespaces,
+                                    raw_output.lines(),
+                                    raw_output.ends_with('\n'),
+                                );
+                                Some(Chunk::Text(output, Some(origin)))
+                            } else {
+                                None
+                            }
                         } else {
                             None
                         }
-                    } else {
-                        None
                     };
                     let has_tail = if line.is_some() {
                         self.execute_tail_line = line;
@@ -114,19 +277,21 @@ impl<'a> Pp<'a> {
             if self.pp_mode.is_execute() {
                 if let Some(x) = to_write {
                     if add_newline_before_next_output {
-                        self.context.write_output(self.context.line_ending)?;
+                        let line_ending = self.context.line_ending.to_string();
+                        self.write_chunk(Chunk::Text(line_ending, None))?;
                     }
                     add_newline_before_next_output = !has_tail;
-                    self.context.write_output(&x)?;
+                    self.write_chunk(x)?;
                 }
             }
         }
+        self.flush_queued()?;
 
         if let PpMode::CollectDeps(deps) = self.pp_mode {
-            return Ok(PpResult::HasDeps(self.input_file, deps));
+            return Ok(PpOutcome::HasDeps(deps));
         }
 
-        if self.tag_state.has_tags() && !matches!(self.mode, Mode::Clean) {
+        if self.tag_state.has_tags() && !matches!(self.mode, Mode::Clean | Mode::Plan) {
             return Err(
                 Report::from(self.context.make_error(PpErrorKind::Directive))
                     .attach_printable("Unused tag(s) found at the end of the file. Please make sure all created tags are used up.")
@@ -135,12 +300,22 @@ impl<'a> Pp<'a> {
         }
 
         if add_newline_before_next_output && trailing_newline {
-            self.context.write_output(self.context.line_ending)?;
+            self.write_output(self.context.line_ending, None)?;
+        }
+        if self.at_file_start {
+            // the output is empty
+            if let Some(banner) = self.banner.take() {
+                self.write_to_context(&banner, None)?;
+            }
         }
 
-        self.context.done()?;
+        let written = self.context.done()?;
 
-        Ok(PpResult::Ok(self.input_file))
+        Ok(PpOutcome::Done {
+            record: self.record,
+            written,
+            source_map: self.source_map,
+        })
     }
 
     /// retrieve the next line
@@ -178,6 +353,7 @@ impl<'a> Pp<'a> {
                             }
                             // Detected, remove this line
                             self.cur_directive = Some(d);
+                            self.directive_line = self.context.cur_line;
                             IterDirectiveResult::LineTaken
                         },
                         None => {
@@ -205,24 +381,36 @@ impl<'a> Pp<'a> {
         Ok(next)
     }
 
-    /// Execute the directive and return the output from the directive
-    fn execute_directive(&mut self, d: Directive) -> Result<Option<String>, PpError> {
+    /// Execute the directive and return the output from the directive, with where the output is from
+    fn execute_directive(&mut self, d: Directive) -> Result<Option<(String, Origin)>, PpError> {
         if let Mode::Clean = self.mode {
             // Ignore error if in clean mode
             let _ = self.execute_in_clean_mode(d);
             return Ok(None);
         }
+        if let Mode::Plan = self.mode {
+            self.execute_in_plan_mode(d)?;
+            return Ok(None);
+        }
         let d = match self.execute_in_collect_deps_mode(d)? {
             Some(d) => d,
             None => return Ok(None),
         };
 
+        let directive = self.observer.map(|_| d.to_string());
         let raw_output = match d.directive_type {
-            DirectiveType::Empty | DirectiveType::After => {
+            DirectiveType::Empty => {
                 // do nothing (consume the line)
                 None
             }
-            DirectiveType::Run => {
+            DirectiveType::After => {
+                // only record the dependency (consume the line)
+                let arg = d.args.into_iter().next().unwrap_or_default();
+                let after_file = self.resolve_dep(&arg);
+                self.record.deps.push(after_file);
+                None
+            }
+            DirectiveType::Run | DirectiveType::RunSeq => {
                 let command = d.args.join(" ");
                 let output = self
                     .shell
@@ -231,25 +419,41 @@ impl<'a> Pp<'a> {
                         e.change_context(self.context.make_error(PpErrorKind::Directive))
                             .attach_printable(format!("failed to run command: `{command}`."))
                     })?;
-                Some(output)
+                let origin = Origin::Run {
+                    line: self.directive_line,
+                    command,
+                };
+                Some((output, origin))
             }
             DirectiveType::Include => {
                 let arg = d.args.into_iter().next().unwrap_or_default();
+                // generated files are read from the output directory
+                let generated = self.out_dir.resolve_generated(
+                    &self.context.work_dir.as_path().join(&arg),
+                    self.context.work_dir.fs(),
+                );
                 let include_file = self
                     .context
                     .work_dir
-                    .try_resolve(&arg, false)
+                    .try_resolve(&generated, false)
                     .map_err(|e| {
                         e.change_context(self.context.make_error(PpErrorKind::Directive))
                             .attach_printable(format!("could not open include file: `{arg}`"))
                     })?;
-                let output = std::fs::read_to_string(&include_file)
+                let output = include_file
+                    .fs()
+                    .read_to_string(include_file.as_path())
                     .change_context_lazy(|| self.context.make_error(PpErrorKind::Directive))
                     .attach_printable_lazy(|| {
                         format!("could not read include file: `{include_file}`")
                     })?;
                 log::debug!("include file content: {output:?}");
-                Some(output)
+                let origin = Origin::Include {
+                    file: include_file.to_string(),
+                    line: 1,
+                };
+                self.record.deps.push(include_file.into_path_buf());
+                Some((output, origin))
             }
             DirectiveType::Temp => {
                 self.execute_directive_temp(d.args, false)?;
@@ -264,11 +468,191 @@ impl<'a> Pp<'a> {
                 })?;
                 None
             }
-            DirectiveType::Write => Some(d.args.join("\n")),
+            DirectiveType::Write => {
+                let origin = Origin::Write {
+                    line: self.directive_line,
+                };
+                Some((d.args.join("\n"), origin))
+            }
         };
+        if let (Some(observer), Some(directive)) = (self.observer, directive) {
+            observer.notify(&Event::DirectiveExecuted {
+                file: &self.context.input_path,
+                line: self.directive_line,
+                directive: &directive,
+            });
+        }
         Ok(raw_output)
     }
 
+    /// If the directive is a `run` directive that can run in the background
+    ///
+    /// Commands whose output is stored in a tag are never run in the background, since the tag
+    /// needs to be injected into the lines that follow.
+    fn can_run_in_background(&self, d: &Directive) -> bool {
+        d.directive_type == DirectiveType::Run
+            && self.pp_mode.is_execute()
+            && !matches!(self.mode, Mode::Clean | Mode::Plan)
+            && !self.tag_state.is_listening()
+    }
+
+    /// Start running the command of the `run` directive in the pool
+    fn spawn_run(&self, run_pool: &ThreadPool, d: Directive) -> BackgroundRun {
+        let command = d.args.join(" ");
+        let shell = self.shell.clone();
+        let work_dir = self.context.work_dir.clone();
+        let file = self.context.input_path.clone();
+        let (send, recv) = mpsc::channel();
+        {
+            let command = command.clone();
+            run_pool.execute(move || {
+                // the receiver is dropped if the file failed before the output is needed
+                let _ = send.send(shell.run(&command, &work_dir, &file));
+            });
+        }
+        BackgroundRun {
+            recv,
+            directive: d.to_string(),
+            whitespaces: d.whitespaces,
+            command,
+            line: self.directive_line,
+        }
+    }
+
+    /// Write the output, or queue it if there are commands running in the background
+    fn write_chunk(&mut self, chunk: Chunk) -> Result<(), PpError> {
+        match chunk {
+            Chunk::Text(text, origin) if self.queued.is_empty() => self.write_output(&text, origin),
+            chunk => {
+                self.queued.push(chunk);
+                Ok(())
+            }
+        }
+    }
+
+    /// Wait for the commands running in the background, and write the queued output in order
+    fn flush_queued(&mut self) -> Result<(), PpError> {
+        for chunk in std::mem::take(&mut self.queued) {
+            let (text, origin) = match chunk {
+                Chunk::Text(text, origin) => (text, origin),
+                Chunk::Run(run) => {
+                    let (text, origin) = self.finish_run(run)?;
+                    (text, Some(origin))
+                }
+            };
+            self.write_output(&text, origin)?;
+        }
+        Ok(())
+    }
+
+    /// Write to the output, and record where the text is from in the source map
+    ///
+    /// A line marker is written before the text if it doesn't continue the lines before it.
+    fn write_output(&mut self, text: &str, origin: Option<Origin>) -> Result<(), PpError> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        if let (Some(marker), Some(origin)) = (self.line_marker, &origin) {
+            if self.at_line_start && self.next_origin.as_ref() != Some(origin) {
+                let marker = match origin {
+                    Origin::Source { line } | Origin::Run { line, .. } | Origin::Write { line } => {
+                        marker.format(*line, &self.context.input_path)
+                    }
+                    Origin::Include { file, line } => marker.format(*line, file),
+                };
+                let marker = format!("{marker}{}", self.context.line_ending);
+                self.write_raw(&marker, None)?;
+            }
+            self.next_origin = match origin {
+                Origin::Source { line } if !text.contains('\n') => {
+                    Some(Origin::Source { line: line + 1 })
+                }
+                _ => None,
+            };
+        }
+        self.write_raw(text, origin)
+    }
+
+    /// Write to the output without line markers
+    ///
+    /// The banner is written before the first line, or after it if it's a shebang.
+    fn write_raw(&mut self, text: &str, origin: Option<Origin>) -> Result<(), PpError> {
+        if let Some(banner) = self.banner.take() {
+            if self.at_file_start && !text.starts_with("#!") {
+                self.write_to_context(&banner, None)?;
+            } else {
+                match text.find('\n') {
+                    Some(i) => {
+                        self.write_to_context(&text[..=i], origin.clone())?;
+                        self.write_to_context(&banner, None)?;
+                        let origin = origin.map(|origin| match origin {
+                            Origin::Source { line } => Origin::Source { line: line + 1 },
+                            Origin::Include { file, line } => Origin::Include {
+                                file,
+                                line: line + 1,
+                            },
+                            origin => origin,
+                        });
+                        return self.write_to_context(&text[i + 1..], origin);
+                    }
+                    None => self.banner = Some(banner),
+                }
+            }
+        }
+        self.write_to_context(text, origin)
+    }
+
+    /// Write to the output and record where the text is from
+    fn write_to_context(&mut self, text: &str, origin: Option<Origin>) -> Result<(), PpError> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.at_file_start = false;
+        if let Some(source_map) = &mut self.source_map {
+            source_map.add(text, origin);
+        }
+        self.at_line_start = text.ends_with('\n');
+        self.context.write_output(text)
+    }
+
+    /// Wait for the command running in the background and format its output
+    fn finish_run(&mut self, run: BackgroundRun) -> Result<(String, Origin), PpError> {
+        let error = PpError {
+            kind: PpErrorKind::Directive,
+            file: self.context.input_path.clone(),
+            line: run.line,
+        };
+        let raw_output = match run.recv.recv() {
+            Ok(result) => result.map_err(|e| {
+                e.change_context(error)
+                    .attach_printable(format!("failed to run command: `{}`.", run.command))
+            }),
+            Err(_) => Err(Report::new(error).attach_printable(format!(
+                "thread panicked while running command: `{}`.",
+                run.command
+            ))),
+        }
+        .map_err(|e| e.attach_printable(format!("for `{}`", run.directive)))?;
+        log::debug!("directive output: {raw_output:?}");
+        if let Some(observer) = self.observer {
+            observer.notify(&Event::DirectiveExecuted {
+                file: &self.context.input_path,
+                line: run.line,
+                directive: &run.directive,
+            });
+        }
+        let output = self.format_directive_output(
+            &run.whitespaces,
+            raw_output.lines(),
+            raw_output.ends_with('\n'),
+        );
+        let origin = Origin::Run {
+            line: run.line,
+            command: run.command,
+        };
+        Ok((output, origin))
+    }
+
     /// Execute the directive in clean mode
     fn execute_in_clean_mode(&mut self, d: Directive) -> Result<(), PpError> {
         if let DirectiveType::Temp = d.directive_type {
@@ -277,8 +661,57 @@ impl<'a> Pp<'a> {
         Ok(())
     }
 
+    /// Execute the directive in plan mode
+    ///
+    /// Only dependencies and temp files are recorded. Nothing is run or written.
+    fn execute_in_plan_mode(&mut self, d: Directive) -> Result<(), PpError> {
+        match d.directive_type {
+            DirectiveType::Include | DirectiveType::After => {
+                let arg = d.args.first().cloned().unwrap_or_default();
+                let dep = self.resolve_dep(&arg);
+                self.record.deps.push(dep);
+            }
+            DirectiveType::Temp => {
+                let export_file = self.get_temp_export_file(&d.args)?;
+                let temp_file = self.context.work_dir.as_path().join(export_file);
+                self.record.temps.push(temp_file);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Resolve the path of an `include` or `after` target, which may not exist yet
+    fn resolve_dep(&self, arg: &str) -> PathBuf {
+        // a target that doesn't exist yet can't be canonicalized, so `.` and `..` are resolved here,
+        // for it to be matched with the output of a `.txtpp` file
+        let p = self.out_dir.resolve_generated(
+            &clean_path(&self.context.work_dir.as_path().join(arg)),
+            self.context.work_dir.fs(),
+        );
+        match self.context.work_dir.try_resolve(&p, false) {
+            Ok(p) => p.into_path_buf(),
+            Err(_) => p,
+        }
+    }
+
     /// Execute the directive in collect dep mode
     fn execute_in_collect_deps_mode(&mut self, d: Directive) -> Result<Option<Directive>, PpError> {
+        if self.symlinks == SymlinkPolicy::Skip
+            && matches!(
+                d.directive_type,
+                DirectiveType::Include | DirectiveType::After
+            )
+        {
+            let arg = d.args.first().cloned().unwrap_or_default();
+            let path = self.context.work_dir.as_path().join(&arg);
+            if self.context.work_dir.fs().is_symlink(&path) {
+                return Err(Report::new(self.context.make_error(PpErrorKind::Directive))
+                    .attach_printable(format!(
+                        "`{arg}` is a symbolic link, which is not followed."
+                    )));
+            }
+        }
         if let PpMode::Execute = self.pp_mode {
             // never collect deps in execute mode
             return Ok(Some(d));
@@ -292,7 +725,10 @@ impl<'a> Pp<'a> {
             // We use join instead of share_base because the dependency might not exist
             let include_path = self.context.work_dir.as_path().join(include_path);
             // See if we need to store the dependency and come back later
-            if let Some(x) = include_path.get_txtpp_file() {
+            if let Some(x) = self
+                .out_dir
+                .source_of(&include_path, self.context.work_dir.fs())
+            {
                 log::debug!("found dependency: {}", x.display());
                 let p_abs = self.context.work_dir.share_base(x).map_err(|e| {
                     e.change_context(self.context.make_error(PpErrorKind::Directive))
@@ -321,6 +757,21 @@ impl<'a> Pp<'a> {
     }
 
     fn execute_directive_temp(&mut self, args: Vec<String>, is_clean: bool) -> Result<(), PpError> {
+        let export_file = self.get_temp_export_file(&args)?;
+
+        if is_clean {
+            return self.context.write_temp_file(export_file, "");
+        }
+        // We force trailing newline if the file is not empty
+        let contents = self.format_directive_output("", args.iter().skip(1), false);
+        self.context.write_temp_file(export_file, &contents)?;
+        let temp_file = self.context.work_dir.as_path().join(export_file);
+        self.record.temps.push(temp_file);
+        Ok(())
+    }
+
+    /// Get the export file path from the arguments of a `temp` directive
+    fn get_temp_export_file<'b>(&self, args: &'b [String]) -> Result<&'b String, PpError> {
         let export_file = match args.first() {
             Some(p) => p,
             None => {
@@ -335,13 +786,7 @@ impl<'a> Pp<'a> {
                 "invalid temp directive: export file path cannot be a txtpp file: `{export_file}`"
             )));
         }
-
-        if is_clean {
-            return self.context.write_temp_file(export_file, "");
-        }
-        // We force trailing newline if the file is not empty
-        let contents = self.format_directive_output("", args.iter().skip(1), false);
-        self.context.write_temp_file(export_file, &contents)
+        Ok(export_file)
     }
 
     fn format_directive_output(
@@ -361,6 +806,51 @@ impl<'a> Pp<'a> {
     }
 }
 
+/// Write the source map for the output of the txtpp file, or remove it when cleaning
+fn update_source_map(
+    opts: &PpOpts,
+    input_file: &AbsPath,
+    output_file: &Path,
+    source_map: Option<SourceMapBuilder>,
+) -> Result<(), PpError> {
+    if !opts.source_map {
+        return Ok(());
+    }
+    let mut path = output_file.as_os_str().to_owned();
+    path.push(SOURCE_MAP_EXT);
+    let path = PathBuf::from(path);
+    let fs = input_file.fs();
+    let make_error = |kind| PpError {
+        kind,
+        file: input_file.to_string(),
+        line: 0,
+    };
+    match (&opts.mode, source_map) {
+        (Mode::Build | Mode::InMemoryBuild, Some(source_map)) => {
+            let content = source_map
+                .finish(
+                    input_file.display_with_base(output_file),
+                    input_file.to_string(),
+                )
+                .to_json();
+            if fs.read_to_string(&path).ok().as_ref() == Some(&content) {
+                // don't touch the source map if it's the same
+                return Ok(());
+            }
+            write_atomic(fs, &path, content.as_bytes())
+                .change_context_lazy(|| make_error(PpErrorKind::WriteFile))
+                .attach_printable_lazy(|| {
+                    format!("could not write source map: `{}`", path.display())
+                })
+        }
+        (Mode::Clean, _) if fs.exists(&path) => fs
+            .remove_file(&path)
+            .change_context_lazy(|| make_error(PpErrorKind::DeleteFile))
+            .attach_printable_lazy(|| format!("could not remove source map: `{}`", path.display())),
+        _ => Ok(()),
+    }
+}
+
 trait IgnoreIfCleaning {
     type Output;
     fn ignore_err_if_cleaning<F>(self, mode: &Mode, f: F) -> Result<Self::Output, PpError>
@@ -383,6 +873,26 @@ impl<T> IgnoreIfCleaning for Result<T, PpError> {
     }
 }
 
+/// Output of a directive or a line to write
+enum Chunk {
+    /// Output that is ready to be written, and where it's from
+    Text(String, Option<Origin>),
+    /// Output of a command that is running in the background
+    Run(BackgroundRun),
+}
+
+/// A `run` directive whose command is running in the pool
+struct BackgroundRun {
+    /// Receiver of the output, which is disconnected if running the command panicked
+    recv: mpsc::Receiver<Result<String, ShellError>>,
+    /// The directive, for error messages and events
+    directive: String,
+    whitespaces: String,
+    command: String,
+    /// Line number where the directive starts
+    line: usize,
+}
+
 /// Result of reading the next line of a directive
 #[derive(Debug)]
 enum IterDirectiveResult {
@@ -414,7 +924,27 @@ impl PpMode {
 #[derive(Debug)]
 pub enum PpResult {
     /// File was processed successfully
-    Ok(AbsPath),
+    Ok(AbsPath, FileRecord),
+    /// File was processed successfully, but the output already had the fresh content and was not touched.
+    ///
+    /// This is only possible in [`Mode::InMemoryBuild`]
+    Unchanged(AbsPath, FileRecord),
+    /// File is up-to-date and was not processed. The record is from a previous run
+    Fresh(AbsPath, FileRecord),
     /// Dependency is found
     HasDeps(AbsPath, Vec<AbsPath>),
 }
+
+/// Result of running the preprocessor on the input, regardless of where the input is from
+enum PpOutcome {
+    /// The input was processed
+    Done {
+        record: FileRecord,
+        /// If the output was written
+        written: bool,
+        /// Where the lines in the output are from, if the source map is written
+        source_map: Option<SourceMapBuilder>,
+    },
+    /// Dependencies need to be processed first
+    HasDeps(Vec<AbsPath>),
+}
@@ -0,0 +1,74 @@
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Summary of a successful run, returned from [`Txtpp::run`](super::Txtpp::run)
+///
+/// # Example
+/// ```no_run
+/// use txtpp::{Config, FileStatus, Txtpp};
+///
+/// let report = Txtpp::run(Config::default()).unwrap();
+/// for file in report.with_status(FileStatus::Written) {
+///     println!("{} -> {}", file.input.display(), file.output.display());
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RunReport {
+    /// The `.txtpp` files, in the order they finished
+    pub files: Vec<FileReport>,
+    /// Time of the whole run
+    pub duration: Duration,
+    /// The build plan in [`Mode::Plan`](crate::Mode::Plan), which lists the files in the order they would be built.
+    ///
+    /// If [`Config::graph_format`](crate::Config::graph_format) is set, this is the dependency graph in that format instead
+    pub plan: Option<String>,
+    /// Files of the `.txtpp` files that were deleted, which are removed when cleaning with [`Config::orphans`](crate::Config::orphans).
+    ///
+    /// With [`Config::dry_run`](crate::Config::dry_run), these are the files that would be removed
+    pub orphan_files: Vec<PathBuf>,
+}
+
+impl RunReport {
+    /// Get the files with the status
+    pub fn with_status(&self, status: FileStatus) -> impl Iterator<Item = &FileReport> {
+        self.files.iter().filter(move |f| f.status == status)
+    }
+}
+
+/// Summary of one `.txtpp` file in a [`RunReport`]
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    /// The absolute path of the `.txtpp` file
+    pub input: PathBuf,
+    /// The absolute path of the output file
+    pub output: PathBuf,
+    /// What was done to the file
+    pub status: FileStatus,
+    /// Time spent processing the file, including the passes to find its dependencies.
+    ///
+    /// This is zero for [`FileStatus::Fresh`] files
+    pub duration: Duration,
+    /// Files from `include` and `after` directives
+    pub deps: Vec<PathBuf>,
+    /// Files written by `temp` directives
+    pub temps: Vec<PathBuf>,
+}
+
+/// What was done to a `.txtpp` file in a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+    /// The output was written
+    Written,
+    /// The output already had the fresh content and was not touched. See [`Mode::InMemoryBuild`](crate::Mode::InMemoryBuild)
+    Unchanged,
+    /// The file was up-to-date according to the incremental build cache and was not processed
+    Fresh,
+    /// The output and temporary files were removed
+    Cleaned,
+    /// The output was verified to be up-to-date
+    Verified,
+    /// Nothing was done in [`Mode::Plan`](crate::Mode::Plan)
+    Planned,
+}
@@ -1,19 +1,33 @@
+use super::SymlinkPolicy;
 use crate::error::PathError;
-use crate::fs::{AbsPath, Directory, TxtppPath};
+use crate::fs::{AbsPath, Directory, OutDir, TxtppPath};
 use error_stack::{Report, Result};
 
-pub fn resolve_inputs(inputs: &[String], base_abs_path: &AbsPath) -> Result<Directory, PathError> {
+/// Resolve the input files and directories
+///
+/// Output files given as input are resolved to their `.txtpp` files, which may be in the output directory.
+/// Inputs that are symbolic links are errors with [`SymlinkPolicy::Skip`].
+pub fn resolve_inputs(
+    inputs: &[String],
+    base_abs_path: &AbsPath,
+    out_dir: &OutDir,
+    symlinks: SymlinkPolicy,
+) -> Result<Directory, PathError> {
     let mut directory = Directory::new();
     for input in inputs {
         let input_path = base_abs_path.as_path().join(input);
-        if input_path.is_dir() {
+        if symlinks == SymlinkPolicy::Skip && base_abs_path.fs().is_symlink(&input_path) {
+            return Err(Report::new(PathError::from(&input_path))
+                .attach_printable("input is a symbolic link, which is not followed."));
+        }
+        if base_abs_path.fs().is_dir(&input_path) {
             let abs_path = base_abs_path.share_base(input_path)?;
             // if input is directory, add to the directories to scan
             directory.subdirs.push(abs_path);
         } else if !input_path.is_txtpp_file() {
             // input is a file but not a txtpp file
             // not that input file doesn't have to exist
-            if let Some(input_path) = input_path.get_txtpp_file() {
+            if let Some(input_path) = out_dir.source_of(&input_path, base_abs_path.fs()) {
                 let abs_path = base_abs_path.share_base(input_path)?;
                 directory.files.push(abs_path);
             } else {
@@ -1,32 +1,363 @@
+use super::{Config, SymlinkPolicy};
 use crate::error::PathError;
-use crate::fs::{AbsPath, Directory, TxtppPath};
-use error_stack::{Result, ResultExt};
+use crate::fs::{AbsPath, Directory, FileSystem, TxtppPath};
+use error_stack::{Report, Result, ResultExt};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-pub fn scan_dir(dir: &AbsPath, recursive: bool) -> Result<Directory, PathError> {
+/// Name of the ignore file with `.gitignore` syntax, which is always honored when scanning
+pub const IGNORE_FILE: &str = ".txtppignore";
+/// Name of the git ignore file, which is honored when [`Config::gitignore`](super::Config::gitignore) is enabled
+const GIT_IGNORE_FILE: &str = ".gitignore";
+
+/// Filters from the config for files and directories found while scanning
+#[derive(Debug)]
+pub struct ScanFilter {
+    /// Patterns that a `.txtpp` file must match one of, if any
+    include: Option<Gitignore>,
+    /// Patterns of files and directories to skip
+    exclude: Option<Gitignore>,
+    /// If `.gitignore` files are honored
+    gitignore: bool,
+    /// How to treat symbolic links
+    symlinks: SymlinkPolicy,
+    /// The maximum depth of subdirectories to scan
+    max_depth: Option<usize>,
+}
+
+impl Default for ScanFilter {
+    fn default() -> Self {
+        Self {
+            include: None,
+            exclude: None,
+            gitignore: false,
+            symlinks: SymlinkPolicy::FollowOnce,
+            max_depth: None,
+        }
+    }
+}
+
+impl ScanFilter {
+    /// Create the filter from the config, with patterns relative to the base directory
+    pub fn new(base: &AbsPath, config: &Config) -> Result<Self, PathError> {
+        Ok(Self {
+            include: build_patterns(base.as_path(), &config.include)?,
+            exclude: build_patterns(base.as_path(), &config.exclude)?,
+            gitignore: config.gitignore,
+            symlinks: config.symlinks,
+            max_depth: config.max_depth,
+        })
+    }
+
+    /// Get the scope of an input directory, with the ignore files in the directories
+    /// from `base` down to `dir`, excluding `dir`
+    ///
+    /// This is used for directories in the inputs, which are not found by scanning their parents.
+    pub fn scope_of(&self, base: &AbsPath, dir: &AbsPath) -> Result<ScanScope, PathError> {
+        let mut scope = ScanScope::default();
+        let relative = match dir.as_path().strip_prefix(base.as_path()) {
+            Ok(relative) => relative,
+            Err(_) => return Ok(scope),
+        };
+        let mut current = base.as_path().to_path_buf();
+        for component in relative.components() {
+            scope.ignores = self.load_ignores(base.fs(), &current, &scope.ignores)?;
+            current.push(component);
+        }
+        Ok(scope)
+    }
+
+    /// Add the ignore files in `dir` to the ignores from its parents
+    fn load_ignores(
+        &self,
+        fs: &dyn FileSystem,
+        dir: &Path,
+        parent: &Ignores,
+    ) -> Result<Ignores, PathError> {
+        let mut ignores = parent.clone();
+        let mut names = vec![IGNORE_FILE];
+        if self.gitignore {
+            // .txtppignore is added last to take precedence
+            names.insert(0, GIT_IGNORE_FILE);
+        }
+        for name in names {
+            let path = dir.join(name);
+            if !fs.is_file(&path) {
+                continue;
+            }
+            let content = fs
+                .read_to_string(&path)
+                .change_context_lazy(|| PathError::from(&path))
+                .attach_printable("failed to read ignore file")?;
+            let lines = content.lines().map(str::to_string).collect::<Vec<_>>();
+            if let Some(matcher) = build_patterns(dir, &lines)? {
+                ignores.0.push(Arc::new(matcher));
+            }
+        }
+        Ok(ignores)
+    }
+
+    /// If the path found while scanning should be skipped
+    fn is_skipped(&self, path: &Path, is_dir: bool, ignores: &Ignores) -> bool {
+        // the deepest ignore file decides
+        for matcher in ignores.0.iter().rev() {
+            let matched = matched(matcher, path, is_dir);
+            if matched.is_ignore() {
+                return true;
+            }
+            if matched.is_whitelist() {
+                break;
+            }
+        }
+        // a pattern matching a parent directory also matches the files in it
+        if let Some(exclude) = &self.exclude {
+            if matched_or_parents(exclude, path, is_dir).is_ignore() {
+                return true;
+            }
+        }
+        if is_dir {
+            return false;
+        }
+        match &self.include {
+            Some(include) => !matched_or_parents(include, path, false).is_ignore(),
+            None => false,
+        }
+    }
+}
+
+/// Ignore files that apply to a directory, from the outermost to the innermost
+#[derive(Debug, Default, Clone)]
+pub struct Ignores(Vec<Arc<Gitignore>>);
+
+/// State passed down from a directory to the subdirectories found by scanning it
+#[derive(Debug, Default, Clone)]
+pub struct ScanScope {
+    /// The ignore files that apply
+    ignores: Ignores,
+    /// Depth under the input directory
+    depth: usize,
+    /// The directories being scanned from the input directory down to the parent
+    ancestors: Vec<PathBuf>,
+}
+
+/// Match the path if it's under the root of the matcher
+fn matched<'a>(
+    matcher: &'a Gitignore,
+    path: &Path,
+    is_dir: bool,
+) -> ignore::Match<&'a ignore::gitignore::Glob> {
+    if !path.starts_with(matcher.path()) {
+        return ignore::Match::None;
+    }
+    matcher.matched(path, is_dir)
+}
+
+/// Like [`matched`], but the parent directories under the root of the matcher are also checked
+fn matched_or_parents<'a>(
+    matcher: &'a Gitignore,
+    path: &Path,
+    is_dir: bool,
+) -> ignore::Match<&'a ignore::gitignore::Glob> {
+    if !path.starts_with(matcher.path()) {
+        return ignore::Match::None;
+    }
+    matcher.matched_path_or_any_parents(path, is_dir)
+}
+
+/// Build a matcher from lines in `.gitignore` syntax. Returns `None` if there are no patterns
+fn build_patterns(root: &Path, lines: &[String]) -> Result<Option<Gitignore>, PathError> {
+    let mut builder = GitignoreBuilder::new(root);
+    for line in lines {
+        builder.add_line(None, line).map_err(|e| {
+            Report::new(e)
+                .change_context(PathError::from(&root))
+                .attach_printable(format!("invalid pattern: `{line}`"))
+        })?;
+    }
+    let matcher = builder.build().map_err(|e| {
+        Report::new(e)
+            .change_context(PathError::from(&root))
+            .attach_printable("invalid patterns")
+    })?;
+    if matcher.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(matcher))
+}
+
+/// Scan the directory for `.txtpp` files and subdirectories
+///
+/// Returns the scope for the subdirectories, which includes the ignore files in this directory.
+pub fn scan_dir(
+    dir: &AbsPath,
+    recursive: bool,
+    filter: &ScanFilter,
+    scope: &ScanScope,
+) -> Result<(Directory, ScanScope), PathError> {
     let dir_path = dir.as_path_buf();
-    let entries = dir_path
-        .read_dir()
+    let fs = dir.fs();
+    let entries = fs
+        .read_dir(dir_path)
         .change_context_lazy(|| PathError::from(&dir_path))
         .attach_printable("failed to read directory")?;
+    let mut ancestors = scope.ancestors.clone();
+    ancestors.push(dir_path.clone());
+    let sub_scope = ScanScope {
+        ignores: filter.load_ignores(fs, dir_path, &scope.ignores)?,
+        depth: scope.depth + 1,
+        ancestors,
+    };
+    let scan_subdirs = recursive && !matches!(filter.max_depth, Some(max) if scope.depth >= max);
 
     let mut directory = Directory::new();
 
-    for entry in entries {
-        let entry = entry
-            .change_context_lazy(|| PathError::from(&dir_path))
-            .attach_printable("failed to read directory entry")?;
-        let path = entry.path();
-
-        if path.is_file() {
-            if path.is_txtpp_file() {
+    for path in entries {
+        if filter.symlinks == SymlinkPolicy::Skip && fs.is_symlink(&path) {
+            log::debug!("skipping symbolic link: {}", path.display());
+            continue;
+        }
+        if fs.is_file(&path) {
+            if path.is_txtpp_file() && !filter.is_skipped(&path, false, &sub_scope.ignores) {
                 let path_abs = dir.share_base(path)?;
                 directory.files.push(path_abs);
             }
-        } else if path.is_dir() && recursive {
-            let path_abs = dir.share_base(path)?;
+        } else if fs.is_dir(&path)
+            && scan_subdirs
+            && !filter.is_skipped(&path, true, &sub_scope.ignores)
+        {
+            let path_abs = dir.share_base(path.clone())?;
+            if sub_scope.ancestors.contains(path_abs.as_path_buf()) {
+                if filter.symlinks == SymlinkPolicy::Follow {
+                    return Err(Report::new(PathError::from(&path))
+                        .attach_printable(format!("symbolic link loops back to `{path_abs}`")));
+                }
+                log::debug!("skipping symbolic link loop: {}", path.display());
+                continue;
+            }
             directory.subdirs.push(path_abs);
         }
     }
 
-    Ok(directory)
+    Ok((directory, sub_scope))
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+    use crate::fs::MemoryFs;
+
+    fn scan(fs: MemoryFs, filter: impl Fn(&AbsPath) -> ScanFilter) -> Vec<String> {
+        let base = AbsPath::create_base("/".into(), Arc::new(fs)).unwrap();
+        let filter = filter(&base);
+        let mut found = vec![];
+        let mut dirs = vec![(base.clone(), ScanScope::default())];
+        while let Some((dir, scope)) = dirs.pop() {
+            let (directory, scope) = scan_dir(&dir, true, &filter, &scope).unwrap();
+            found.extend(directory.files.iter().map(|f| f.to_string()));
+            dirs.extend(directory.subdirs.into_iter().map(|d| (d, scope.clone())));
+        }
+        found.sort();
+        found
+    }
+
+    fn tree() -> MemoryFs {
+        let fs = MemoryFs::new();
+        fs.insert("a.txt.txtpp", "");
+        fs.insert("node_modules/b.txt.txtpp", "");
+        fs.insert("docs/c.md.txtpp", "");
+        fs.insert("docs/d.txt.txtpp", "");
+        fs.insert("docs/vendor/e.txt.txtpp", "");
+        fs
+    }
+
+    #[test]
+    fn test_no_filter() {
+        let found = scan(tree(), |_| ScanFilter::default());
+        assert_eq!(found.len(), 5);
+    }
+
+    #[test]
+    fn test_ignore_file() {
+        let fs = tree();
+        fs.insert(IGNORE_FILE, "node_modules/\n");
+        fs.insert("docs/.txtppignore", "*.txt.txtpp\n!d.txt.txtpp\n");
+        let found = scan(fs, |_| ScanFilter::default());
+        assert_eq!(
+            found,
+            vec!["a.txt.txtpp", "docs/c.md.txtpp", "docs/d.txt.txtpp"]
+        );
+    }
+
+    #[test]
+    fn test_gitignore() {
+        let fs = tree();
+        fs.insert(GIT_IGNORE_FILE, "node_modules\n");
+        let found = scan(fs.clone(), |_| ScanFilter::default());
+        assert_eq!(found.len(), 5);
+        let found = scan(fs, |_| ScanFilter {
+            gitignore: true,
+            ..Default::default()
+        });
+        assert_eq!(found.len(), 4);
+    }
+
+    #[test]
+    fn test_include_exclude() {
+        let found = scan(tree(), |base| {
+            let config = Config {
+                include: vec!["*.txt.txtpp".to_string()],
+                exclude: vec!["vendor".to_string(), "/node_modules".to_string()],
+                ..Default::default()
+            };
+            ScanFilter::new(base, &config).unwrap()
+        });
+        assert_eq!(found, vec!["a.txt.txtpp", "docs/d.txt.txtpp"]);
+    }
+
+    #[test]
+    fn test_include_directory() {
+        for pattern in ["docs", "docs/", "/docs"] {
+            let found = scan(tree(), |base| {
+                let config = Config {
+                    include: vec![pattern.to_string()],
+                    exclude: vec!["docs/vendor/".to_string()],
+                    ..Default::default()
+                };
+                ScanFilter::new(base, &config).unwrap()
+            });
+            assert_eq!(
+                found,
+                vec!["docs/c.md.txtpp", "docs/d.txt.txtpp"],
+                "pattern: {pattern}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_scope_of() {
+        let fs = tree();
+        fs.insert(IGNORE_FILE, "vendor\n");
+        let base = AbsPath::create_base("/".into(), Arc::new(fs)).unwrap();
+        let filter = ScanFilter::default();
+        let docs = base.share_base("/docs".into()).unwrap();
+        let scope = filter.scope_of(&base, &docs).unwrap();
+        let (directory, _) = scan_dir(&docs, true, &filter, &scope).unwrap();
+        assert_eq!(directory.files.len(), 2);
+        assert!(directory.subdirs.is_empty());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let found = scan(tree(), |_| ScanFilter {
+            max_depth: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(found.len(), 4);
+        let found = scan(tree(), |_| ScanFilter {
+            max_depth: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(found, vec!["a.txt.txtpp"]);
+    }
 }
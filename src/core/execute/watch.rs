@@ -0,0 +1,148 @@
+use super::{observer_of, Config, Event, Txtpp};
+use crate::core::{DepIndex, Progress};
+use crate::error::TxtppError;
+use crate::fs::{AbsPath, FileSystem, OutDir};
+use error_stack::{Report, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Run txtpp in watch mode with the given config
+///
+/// The inputs are built once, then the files are polled every `interval`.
+/// When a `.txtpp` file, an included file or an `after` target changes, the affected files
+/// and the files depending on them are built again. This function never returns unless the config is invalid.
+///
+/// Errors from rebuilding are printed to stderr and don't stop the watch.
+pub fn watch(config: Config, interval: Duration) -> Result<(), TxtppError> {
+    Watcher::new(config)?.run(interval)
+}
+
+/// Watch runtime that keeps the dependency index across runs
+///
+/// Use [`watch`] to run the watch loop, or use [`Watcher::poll`] to control when to check for changes.
+#[derive(Debug)]
+pub struct Watcher {
+    /// The Config used for every run
+    config: Config,
+    /// Reverse-dependency index
+    index: DepIndex,
+    /// Modification time of the watched paths when they were last checked
+    snapshot: HashMap<PathBuf, Option<SystemTime>>,
+    /// The Progress reporter for status between runs
+    progress: Progress,
+    /// If the initial build has run
+    started: bool,
+    /// Where the outputs are, from the last run
+    out_dir: OutDir,
+}
+
+impl Watcher {
+    /// Create a watcher. The mode in the config must be one of the build modes.
+    pub fn new(mut config: Config) -> Result<Self, TxtppError> {
+        if !config.mode.is_build() {
+            return Err(Report::new(TxtppError::default())
+                .attach_printable(format!("cannot watch in {:?} mode", config.mode)));
+        }
+        // share the observer between runs
+        let observer = observer_of(&config);
+        config.observer = Some(Arc::clone(&observer));
+        let progress = Progress::new(observer);
+        Ok(Self {
+            config,
+            index: DepIndex::new(),
+            snapshot: HashMap::new(),
+            progress,
+            started: false,
+            out_dir: OutDir::new(),
+        })
+    }
+
+    /// Build once, then poll for changes forever
+    pub fn run(mut self, interval: Duration) -> Result<(), TxtppError> {
+        loop {
+            if let Err(e) = self.poll() {
+                eprintln!("{:?}", e);
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    /// Check for changes and rebuild the affected files.
+    ///
+    /// The first call builds all inputs. Returns `true` if a build was run.
+    pub fn poll(&mut self) -> Result<bool, TxtppError> {
+        if !self.started {
+            self.started = true;
+            self.rebuild(HashSet::new())?;
+            return Ok(true);
+        }
+        let changed = self
+            .snapshot
+            .iter()
+            .filter(|(path, modified)| modified_time(self.config.fs.as_ref(), path) != **modified)
+            .map(|(path, _)| path.as_path())
+            .collect::<Vec<_>>();
+        if changed.is_empty() {
+            return Ok(false);
+        }
+        log::info!("changed paths: {changed:?}");
+        let affected = self.index.affected(changed, &self.out_dir);
+        let up_to_date = self
+            .index
+            .files()
+            .filter(|file| !affected.contains(*file))
+            .cloned()
+            .collect();
+        self.rebuild(up_to_date)?;
+        Ok(true)
+    }
+
+    /// Run txtpp with the inputs from config, skipping files that are up-to-date
+    fn rebuild(&mut self, up_to_date: HashSet<AbsPath>) -> Result<(), TxtppError> {
+        let (result, mut paths) = {
+            let mut runtime = Txtpp::new(self.config.clone())?;
+            runtime.index = std::mem::take(&mut self.index);
+            runtime.up_to_date = up_to_date;
+            let result = runtime.execute().map(|_| ());
+            self.index = std::mem::take(&mut runtime.index);
+            self.out_dir = runtime.opts.out_dir.clone();
+            // files that failed are not in the index, but we still need to watch them
+            let paths = runtime
+                .files
+                .iter()
+                .chain(runtime.dirs.iter())
+                .map(|p| p.as_path_buf().clone())
+                .collect::<HashSet<_>>();
+            (result, paths)
+        };
+        // remove files that no longer exist
+        let deleted = self
+            .index
+            .files()
+            .filter(|file| !file.fs().exists(file.as_path()))
+            .cloned()
+            .collect::<Vec<_>>();
+        for file in deleted {
+            self.index.remove(&file);
+        }
+        paths.extend(self.index.paths());
+        self.snapshot = paths
+            .into_iter()
+            .map(|path| {
+                let modified = modified_time(self.config.fs.as_ref(), &path);
+                (path, modified)
+            })
+            .collect();
+        self.progress.notify(&Event::Watching {
+            paths: self.snapshot.len(),
+        });
+        result
+    }
+}
+
+fn modified_time(fs: &dyn FileSystem, path: &Path) -> Option<SystemTime> {
+    fs.modified(path).ok()
+}
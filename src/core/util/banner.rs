@@ -0,0 +1,72 @@
+/// Default text of the banner added to outputs
+pub const DEFAULT_BANNER: &str = "DO NOT EDIT - generated by txtpp from {file}";
+
+/// Make the banner for an output with the extension, with each line of `text` as a comment
+///
+/// `{file}` in the text is replaced with `file`. Returns `None` if the comment syntax of the
+/// extension is not known.
+pub fn make_banner(ext: &str, text: &str, file: &str, line_ending: &str) -> Option<String> {
+    let (start, end) = comment_syntax(ext)?;
+    let text = text.replace("{file}", file);
+    let banner = text
+        .lines()
+        .map(|line| {
+            let line = format!("{start} {line}{end}");
+            line.trim_end().to_string()
+        })
+        .map(|line| format!("{line}{line_ending}"))
+        .collect();
+    Some(banner)
+}
+
+/// Get the start and end of a line comment for the extension
+fn comment_syntax(ext: &str) -> Option<(&'static str, &'static str)> {
+    let syntax = match ext.to_ascii_lowercase().as_str() {
+        "c" | "h" | "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" | "cs" | "java" | "kt" | "kts"
+        | "scala" | "swift" | "go" | "rs" | "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx"
+        | "dart" | "zig" | "proto" | "php" | "jsonc" | "glsl" | "vert" | "frag" | "comp"
+        | "hlsl" | "wgsl" | "scss" | "less" => ("//", ""),
+        "py" | "sh" | "bash" | "zsh" | "fish" | "ps1" | "rb" | "pl" | "r" | "toml" | "yaml"
+        | "yml" | "cmake" | "mk" | "nix" | "conf" | "tf" => ("#", ""),
+        "sql" | "lua" | "hs" | "elm" => ("--", ""),
+        "ini" | "asm" | "s" | "lisp" | "clj" => (";", ""),
+        "tex" | "erl" | "m" => ("%", ""),
+        "css" => ("/*", " */"),
+        "html" | "htm" | "xml" | "svg" | "md" | "vue" => ("<!--", " -->"),
+        _ => return None,
+    };
+    Some(syntax)
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    #[test]
+    fn test_line_comment() {
+        assert_eq!(
+            make_banner("rs", DEFAULT_BANNER, "foo.rs.txtpp", "\n").unwrap(),
+            "// DO NOT EDIT - generated by txtpp from foo.rs.txtpp\n"
+        );
+        assert_eq!(
+            make_banner("PY", "a\n\nb", "", "\r\n").unwrap(),
+            "# a\r\n#\r\n# b\r\n"
+        );
+    }
+
+    #[test]
+    fn test_block_comment() {
+        assert_eq!(
+            make_banner("html", "generated", "", "\n").unwrap(),
+            "<!-- generated -->\n"
+        );
+    }
+
+    #[test]
+    fn test_unknown() {
+        assert_eq!(
+            make_banner("txt", DEFAULT_BANNER, "a.txt.txtpp", "\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use crate::core::{DepIndex, FileRecord};
+use crate::fs::{normalize_path, AbsPath, OutDir};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// Dependency Graph
+///
+/// A snapshot of the [`DepIndex`] with paths relative to the base directory,
+/// used to display the dependencies between files.
+#[derive(Debug, Default, Serialize)]
+pub struct DepGraph {
+    /// Nodes keyed by the txtpp file
+    #[serde(rename = "files")]
+    nodes: BTreeMap<String, DepNode>,
+}
+
+/// A txtpp file in the [`DepGraph`]
+#[derive(Debug, Default, Serialize)]
+pub struct DepNode {
+    /// The txtpp file
+    pub input: String,
+    /// The output file
+    pub output: String,
+    /// Files from `include` and `after` directives
+    pub deps: BTreeSet<String>,
+    /// Files written by `temp` directives
+    pub temps: BTreeSet<String>,
+    /// txtpp files that produce one of the `deps`
+    pub txtpp_deps: BTreeSet<String>,
+}
+
+impl DepGraph {
+    /// Create the graph from the records in the index
+    pub fn new(index: &DepIndex, base: &Path, out_dir: &OutDir) -> Self {
+        // output -> txtpp file
+        let outputs = index
+            .files()
+            .filter_map(|file| {
+                let output = out_dir.output_of(file.as_path()).ok()?;
+                Some((output, relative_to(base, file.as_path())))
+            })
+            .collect::<HashMap<PathBuf, String>>();
+        let mut graph = Self::default();
+        for file in index.files() {
+            let record = match index.get(file) {
+                Some(record) => record,
+                None => continue,
+            };
+            let input = relative_to(base, file.as_path());
+            let output = out_dir
+                .output_of(file.as_path())
+                .map(|p| relative_to(base, &p))
+                .unwrap_or_default();
+            let node = DepNode {
+                input: input.clone(),
+                output,
+                deps: record.deps.iter().map(|p| relative_to(base, p)).collect(),
+                temps: record.temps.iter().map(|p| relative_to(base, p)).collect(),
+                txtpp_deps: record
+                    .deps
+                    .iter()
+                    .filter_map(|p| outputs.get(p).cloned())
+                    .collect(),
+            };
+            graph.nodes.insert(input, node);
+        }
+        graph
+    }
+
+    /// Create the graph from a map of `txtpp file -> [txtpp files it depends on]`,
+    /// such as the remaining graph in [`DepManager`](super::DepManager)
+    pub fn from_dep_map(
+        map: &HashMap<AbsPath, HashSet<AbsPath>>,
+        base: &Path,
+        out_dir: &OutDir,
+    ) -> Self {
+        let mut index = DepIndex::new();
+        for (file, deps) in map {
+            let deps = deps
+                .iter()
+                .filter_map(|dep| out_dir.output_of(dep.as_path()).ok())
+                .collect();
+            index.update(
+                file,
+                FileRecord {
+                    deps,
+                    temps: vec![],
+                },
+            );
+        }
+        Self::new(&index, base, out_dir)
+    }
+
+    /// Get the order to build the files, so that every file comes after the files it depends on.
+    ///
+    /// Files without ordering constraints between them are sorted by path.
+    /// If there are circular dependencies, the files that cannot be ordered are returned as the error.
+    pub fn build_order(&self) -> Result<Vec<&DepNode>, Vec<&DepNode>> {
+        let mut order = vec![];
+        let mut remaining = self.nodes.values().collect::<Vec<_>>();
+        let mut done = BTreeSet::new();
+        while !remaining.is_empty() {
+            let (ready, not_ready): (Vec<_>, Vec<_>) = remaining
+                .into_iter()
+                .partition(|node| node.txtpp_deps.iter().all(|dep| done.contains(dep)));
+            if ready.is_empty() {
+                return Err(not_ready);
+            }
+            done.extend(ready.iter().map(|node| node.input.clone()));
+            order.extend(ready);
+            remaining = not_ready;
+        }
+        Ok(order)
+    }
+
+    /// Display the build plan
+    pub fn to_plan(&self) -> String {
+        let mut out = String::new();
+        let (order, cyclic) = match self.build_order() {
+            Ok(order) => (order, vec![]),
+            Err(cyclic) => (vec![], cyclic),
+        };
+        for (i, node) in order.iter().enumerate() {
+            let _ = writeln!(out, "{:>3}. {}", i + 1, node.input);
+            write_node(&mut out, node);
+        }
+        if !cyclic.is_empty() {
+            let _ = writeln!(out, "Circular dependencies are found:");
+            for node in cyclic {
+                let _ = writeln!(out, "  -  {}", node.input);
+                write_node(&mut out, node);
+            }
+        }
+        out
+    }
+
+    /// Export the graph in the DOT language of Graphviz
+    ///
+    /// txtpp files are drawn in bold, with edges to their output and temp files (dashed).
+    /// Dependencies have edges to the txtpp files that depend on them, so a txtpp file that depends on
+    /// the output of another is connected through that output.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph txtpp {{");
+        let _ = writeln!(out, "    node [shape=box];");
+        for node in self.nodes.values() {
+            let input = dot_id(&node.input);
+            let _ = writeln!(out, "    {input} [style=bold];");
+            let _ = writeln!(out, "    {input} -> {};", dot_id(&node.output));
+            for temp in &node.temps {
+                let _ = writeln!(out, "    {input} -> {} [style=dashed];", dot_id(temp));
+            }
+            for dep in &node.deps {
+                let _ = writeln!(out, "    {} -> {input};", dot_id(dep));
+            }
+        }
+        let _ = writeln!(out, "}}");
+        out
+    }
+
+    /// Export the graph as JSON
+    pub fn to_json(&self) -> String {
+        // serializing maps and sets of strings cannot fail
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+/// Quote the string as an ID in the DOT language
+fn dot_id(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn write_node(out: &mut String, node: &DepNode) {
+    let _ = writeln!(out, "       output: {}", node.output);
+    for temp in &node.temps {
+        let _ = writeln!(out, "       temp: {temp}");
+    }
+    for dep in &node.deps {
+        let _ = writeln!(out, "       depends on: {dep}");
+    }
+}
+
+/// Get the path relative to the base, or the path itself if it's not under the base
+pub fn relative_to(base: &Path, p: &Path) -> String {
+    let p = p.strip_prefix(base).unwrap_or(p);
+    normalize_path(&p.display().to_string()).to_string()
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    fn graph(files: &[(&str, &[&str])]) -> DepGraph {
+        let mut index = DepIndex::new();
+        for (file, deps) in files {
+            index.update(
+                &AbsPath::new(PathBuf::from(file)),
+                FileRecord {
+                    deps: deps.iter().map(PathBuf::from).collect(),
+                    temps: vec![],
+                },
+            );
+        }
+        DepGraph::new(&index, Path::new("/"), &OutDir::new())
+    }
+
+    #[test]
+    fn test_dot() {
+        let g = graph(&[("/a.txtpp", &["/b"]), ("/b.txtpp", &[])]);
+        assert_eq!(
+            g.to_dot(),
+            r#"digraph txtpp {
+    node [shape=box];
+    "a.txtpp" [style=bold];
+    "a.txtpp" -> "a";
+    "b" -> "a.txtpp";
+    "b.txtpp" [style=bold];
+    "b.txtpp" -> "b";
+}
+"#
+        );
+        assert_eq!(dot_id(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+
+    #[test]
+    fn test_from_dep_map() {
+        let a = AbsPath::new(PathBuf::from("/a.txtpp"));
+        let b = AbsPath::new(PathBuf::from("/b.txtpp"));
+        let map = [
+            (a.clone(), [b.clone()].into_iter().collect()),
+            (b, [a].into_iter().collect()),
+        ]
+        .into_iter()
+        .collect();
+        let g = DepGraph::from_dep_map(&map, Path::new("/"), &OutDir::new());
+        assert_eq!(
+            inputs(g.build_order().unwrap_err()),
+            vec!["a.txtpp", "b.txtpp"]
+        );
+    }
+
+    fn inputs(nodes: Vec<&DepNode>) -> Vec<&str> {
+        nodes.into_iter().map(|n| n.input.as_str()).collect()
+    }
+
+    #[test]
+    fn test_empty() {
+        assert!(graph(&[]).build_order().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_order() {
+        let g = graph(&[
+            ("/a.txtpp", &["/b", "/x"]),
+            ("/b.txtpp", &["/c"]),
+            ("/c.txtpp", &[]),
+            ("/d.txtpp", &[]),
+        ]);
+        assert_eq!(
+            inputs(g.build_order().unwrap()),
+            vec!["c.txtpp", "d.txtpp", "b.txtpp", "a.txtpp"]
+        );
+    }
+
+    #[test]
+    fn test_circle() {
+        let g = graph(&[
+            ("/a.txtpp", &["/b"]),
+            ("/b.txtpp", &["/a"]),
+            ("/c.txtpp", &[]),
+        ]);
+        assert_eq!(
+            inputs(g.build_order().unwrap_err()),
+            vec!["a.txtpp", "b.txtpp"]
+        );
+    }
+}
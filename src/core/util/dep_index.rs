@@ -0,0 +1,185 @@
+use crate::fs::{AbsPath, OutDir};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Files used by a txtpp file while it was processed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileRecord {
+    /// Files from `include` and `after` directives
+    pub deps: Vec<PathBuf>,
+    /// Files written by `temp` directives
+    pub temps: Vec<PathBuf>,
+}
+
+/// Dependency Index
+///
+/// Unlike [`DepManager`](super::DepManager), which only lives for one run to schedule the files,
+/// the index keeps the records of processed files across runs. It can be queried in reverse
+/// to find which files need to be processed again when some paths change.
+#[derive(Debug, Default)]
+pub struct DepIndex {
+    /// Records of processed txtpp files
+    records: HashMap<AbsPath, FileRecord>,
+    /// Reverse index. `path -> txtpp files` that read the path
+    dependers: HashMap<PathBuf, HashSet<AbsPath>>,
+}
+
+impl DepIndex {
+    /// Create an empty index
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace the record of a txtpp file
+    pub fn update(&mut self, file: &AbsPath, record: FileRecord) {
+        self.remove(file);
+        for dep in &record.deps {
+            self.dependers
+                .entry(dep.clone())
+                .or_default()
+                .insert(file.clone());
+        }
+        self.records.insert(file.clone(), record);
+    }
+
+    /// Remove a txtpp file from the index
+    pub fn remove(&mut self, file: &AbsPath) {
+        let record = match self.records.remove(file) {
+            Some(record) => record,
+            None => return,
+        };
+        for dep in &record.deps {
+            if let Some(dependers) = self.dependers.get_mut(dep) {
+                dependers.remove(file);
+                if dependers.is_empty() {
+                    self.dependers.remove(dep);
+                }
+            }
+        }
+    }
+
+    /// Get the record of a txtpp file
+    pub fn get(&self, file: &AbsPath) -> Option<&FileRecord> {
+        self.records.get(file)
+    }
+
+    /// Iterate over the txtpp files in the index
+    pub fn files(&self) -> impl Iterator<Item = &AbsPath> {
+        self.records.keys()
+    }
+
+    /// Get all paths that the indexed files read or write, including the txtpp files themselves
+    pub fn paths(&self) -> HashSet<PathBuf> {
+        let mut paths = HashSet::new();
+        for (file, record) in &self.records {
+            paths.insert(file.as_path_buf().clone());
+            paths.extend(record.deps.iter().cloned());
+        }
+        paths
+    }
+
+    /// Get the txtpp files that are affected when the paths change.
+    ///
+    /// A txtpp file is affected if itself or any of its dependencies changed,
+    /// or if it depends on the output or temp files of another affected file.
+    pub fn affected<'a>(
+        &self,
+        changed: impl IntoIterator<Item = &'a Path>,
+        out_dir: &OutDir,
+    ) -> HashSet<AbsPath> {
+        let mut affected = HashSet::new();
+        let mut stack = changed
+            .into_iter()
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        while let Some(path) = stack.pop() {
+            let mut files = self
+                .dependers
+                .get(&path)
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect::<Vec<_>>();
+            if let Some((file, _)) = self.records.get_key_value(path.as_path()) {
+                files.push(file.clone());
+            }
+            for file in files {
+                if !affected.insert(file.clone()) {
+                    continue;
+                }
+                // files produced by the affected file will change
+                if let Ok(output) = out_dir.output_of(file.as_path()) {
+                    stack.push(output);
+                }
+                if let Some(record) = self.records.get(&file) {
+                    stack.extend(record.temps.iter().cloned());
+                }
+            }
+        }
+        affected
+    }
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    fn record(deps: &[&str], temps: &[&str]) -> FileRecord {
+        FileRecord {
+            deps: deps.iter().map(PathBuf::from).collect(),
+            temps: temps.iter().map(PathBuf::from).collect(),
+        }
+    }
+
+    #[test]
+    fn test_empty() {
+        let index = DepIndex::new();
+        assert!(index.affected([Path::new("/a")], &OutDir::new()).is_empty());
+    }
+
+    #[test]
+    fn test_source_changed() {
+        let mut index = DepIndex::new();
+        let a = AbsPath::new(PathBuf::from("/a.txtpp"));
+        index.update(&a, record(&[], &[]));
+        assert_eq!(
+            index.affected([Path::new("/a.txtpp")], &OutDir::new()),
+            [a].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_transitive() {
+        let mut index = DepIndex::new();
+        let a = AbsPath::new(PathBuf::from("/a.txtpp"));
+        let b = AbsPath::new(PathBuf::from("/b.txtpp"));
+        let c = AbsPath::new(PathBuf::from("/c.txtpp"));
+        // a includes the output of b, c includes a temp file written by a
+        index.update(&a, record(&["/b", "/x"], &["/t"]));
+        index.update(&b, record(&[], &[]));
+        index.update(&c, record(&["/t"], &[]));
+        assert_eq!(
+            index.affected([Path::new("/b.txtpp")], &OutDir::new()),
+            [a.clone(), b, c.clone()].into_iter().collect()
+        );
+        assert_eq!(
+            index.affected([Path::new("/x")], &OutDir::new()),
+            [a, c].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_update_removes_old_deps() {
+        let mut index = DepIndex::new();
+        let a = AbsPath::new(PathBuf::from("/a.txtpp"));
+        index.update(&a, record(&["/x"], &[]));
+        index.update(&a, record(&["/y"], &[]));
+        assert!(index.affected([Path::new("/x")], &OutDir::new()).is_empty());
+        assert_eq!(
+            index.affected([Path::new("/y")], &OutDir::new()),
+            [a.clone()].into_iter().collect()
+        );
+        index.remove(&a);
+        assert!(index.affected([Path::new("/y")], &OutDir::new()).is_empty());
+    }
+}
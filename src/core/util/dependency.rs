@@ -1,6 +1,5 @@
 use crate::fs::AbsPath;
 use std::collections::{HashMap, HashSet};
-use std::fmt::Write;
 
 /// Dependency Manager
 ///
@@ -9,6 +8,7 @@ pub struct DepManager {
     out_edge_counts: HashMap<AbsPath, usize>, // Count how many dependencies one vertex has
     in_edges: HashMap<AbsPath, HashSet<AbsPath>>, // V -> K edges (V depends on K)
     finished: HashSet<AbsPath>,               // Set of finished vertices
+    failed: HashSet<AbsPath>,                 // Set of failed or skipped vertices
 }
 
 impl DepManager {
@@ -18,6 +18,7 @@ impl DepManager {
             out_edge_counts: HashMap::new(),
             in_edges: HashMap::new(),
             finished: HashSet::new(),
+            failed: HashSet::new(),
         }
     }
 
@@ -63,6 +64,10 @@ impl DepManager {
             None => return output,
         };
         for depender in in_edges {
+            if self.failed.contains(&depender) {
+                // already skipped because another dependency failed
+                continue;
+            }
             let count = self.out_edge_counts.get_mut(&depender).unwrap();
             if *count <= 1 {
                 self.out_edge_counts.remove(&depender);
@@ -74,6 +79,34 @@ impl DepManager {
         output
     }
 
+    /// Notify a file `B` has failed
+    ///
+    /// All `A`s that depend on `B`, directly or indirectly, can no longer be processed.
+    /// They are removed from the graph and returned.
+    pub fn notify_fail(&mut self, failed: &AbsPath) -> HashSet<AbsPath> {
+        let mut output = HashSet::new();
+        let mut stack = vec![failed.clone()];
+        while let Some(failed) = stack.pop() {
+            self.failed.insert(failed.clone());
+            self.out_edge_counts.remove(&failed);
+            let in_edges = match self.in_edges.remove(&failed) {
+                Some(in_edges) => in_edges,
+                None => continue,
+            };
+            for depender in in_edges {
+                if !self.failed.contains(&depender) && output.insert(depender.clone()) {
+                    stack.push(depender);
+                }
+            }
+        }
+        output
+    }
+
+    /// Check if the file has failed or is skipped because of a failed dependency
+    pub fn is_failed(&self, file: &AbsPath) -> bool {
+        self.failed.contains(file)
+    }
+
     /// Convert the remaining graph to a map of `depender -> [dependencies]`
     pub fn take_remaining(self) -> HashMap<AbsPath, HashSet<AbsPath>> {
         let mut out_edges = HashMap::new();
@@ -89,22 +122,6 @@ impl DepManager {
     }
 }
 
-pub fn print_dep_map(map: &HashMap<AbsPath, HashSet<AbsPath>>) -> String {
-    let mut out = String::new();
-    for (k, v) in map {
-        let _ = writeln!(out, "{k}");
-        for (i, dep) in v.iter().enumerate() {
-            let _ = if i == v.len() - 1 {
-                writeln!(out, "╰╴{dep}")
-            } else {
-                writeln!(out, "├╴{dep}")
-            };
-        }
-        out.push('\n')
-    }
-    out
-}
-
 #[cfg(test)]
 mod ut {
     use std::path::PathBuf;
@@ -133,7 +150,7 @@ mod ut {
         let mut dm = DepManager::new();
         let a = AbsPath::new(PathBuf::from("/a"));
         let b = AbsPath::new(PathBuf::from("/b"));
-        assert!(dm.add_dependency(&a, &[b.clone()]));
+        assert!(dm.add_dependency(&a, std::slice::from_ref(&b)));
         let free = dm.notify_finish(&b);
         assert_eq!(free, [a].into_iter().collect());
     }
@@ -144,7 +161,7 @@ mod ut {
         let a = AbsPath::new(PathBuf::from("/a"));
         let b = AbsPath::new(PathBuf::from("/b"));
         let c = AbsPath::new(PathBuf::from("/c"));
-        assert!(dm.add_dependency(&a, &[b.clone()]));
+        assert!(dm.add_dependency(&a, std::slice::from_ref(&b)));
         let free = dm.notify_finish(&c);
         assert_eq!(free, HashSet::new());
         let a_deps = [b].into_iter().collect::<HashSet<_>>();
@@ -175,8 +192,8 @@ mod ut {
         let c = AbsPath::new(PathBuf::from("/c"));
         let d = AbsPath::new(PathBuf::from("/d"));
         assert!(dm.add_dependency(&a, &[b.clone(), c.clone()]));
-        assert!(dm.add_dependency(&b, &[d.clone()]));
-        assert!(dm.add_dependency(&c, &[d.clone()]));
+        assert!(dm.add_dependency(&b, std::slice::from_ref(&d)));
+        assert!(dm.add_dependency(&c, std::slice::from_ref(&d)));
         let free = dm.notify_finish(&d);
         assert_eq!(free, [b.clone(), c.clone()].into_iter().collect());
         let free = dm.notify_finish(&c);
@@ -192,7 +209,7 @@ mod ut {
         let b = AbsPath::new(PathBuf::from("/b"));
         let c = AbsPath::new(PathBuf::from("/c"));
         assert!(dm.add_dependency(&a, &[b.clone(), c.clone()]));
-        assert!(dm.add_dependency(&b, &[a.clone()]));
+        assert!(dm.add_dependency(&b, std::slice::from_ref(&a)));
         let free = dm.notify_finish(&c);
         assert_eq!(free, HashSet::new());
         let a_deps = [b.clone()].into_iter().collect::<HashSet<_>>();
@@ -212,10 +229,30 @@ mod ut {
         let b = AbsPath::new(PathBuf::from("/b"));
         let free = dm.notify_finish(&b);
         assert_eq!(free, HashSet::new());
-        assert!(!dm.add_dependency(&a, &[b.clone()]));
+        assert!(!dm.add_dependency(&a, std::slice::from_ref(&b)));
         assert!(!dm.add_dependency(&a, &[b.clone(), b.clone()]));
         let free = dm.notify_finish(&b);
         assert_eq!(free, HashSet::new());
         assert_eq!(dm.take_remaining(), HashMap::new());
     }
+
+    #[test]
+    fn test_fail() {
+        let mut dm = DepManager::new();
+        let a = AbsPath::new(PathBuf::from("/a"));
+        let b = AbsPath::new(PathBuf::from("/b"));
+        let c = AbsPath::new(PathBuf::from("/c"));
+        let d = AbsPath::new(PathBuf::from("/d"));
+        // a -> b -> c, a -> d
+        assert!(dm.add_dependency(&a, &[b.clone(), d.clone()]));
+        assert!(dm.add_dependency(&b, std::slice::from_ref(&c)));
+        let skipped = dm.notify_fail(&c);
+        assert_eq!(skipped, [a.clone(), b.clone()].into_iter().collect());
+        assert!(dm.is_failed(&a));
+        assert!(dm.is_failed(&c));
+        assert!(!dm.is_failed(&d));
+        // a is already skipped, so it's not scheduled when d finishes
+        assert_eq!(dm.notify_finish(&d), HashSet::new());
+        assert!(dm.take_remaining().is_empty());
+    }
 }
@@ -0,0 +1,57 @@
+use std::fmt::Write;
+
+/// Make the content of a Makefile-syntax depfile, like the ones from `gcc -MD`
+///
+/// The depfile has one rule with the target and its prerequisites. Each prerequisite also
+/// gets an empty rule, so Make doesn't fail when one of them is deleted.
+pub fn make_depfile<'a>(target: &str, prerequisites: impl IntoIterator<Item = &'a str>) -> String {
+    let prerequisites = prerequisites.into_iter().collect::<Vec<_>>();
+    let mut out = escape_depfile_path(target);
+    out.push(':');
+    for p in &prerequisites {
+        out.push_str(" \\\n  ");
+        out.push_str(&escape_depfile_path(p));
+    }
+    out.push('\n');
+    for p in prerequisites.iter().skip(1) {
+        let _ = write!(out, "\n{}:\n", escape_depfile_path(p));
+    }
+    out
+}
+
+/// Escape special characters in a path for Makefile syntax
+fn escape_depfile_path(p: &str) -> String {
+    let mut out = String::with_capacity(p.len());
+    for c in p.chars() {
+        match c {
+            ' ' => out.push_str("\\ "),
+            '#' => out.push_str("\\#"),
+            '$' => out.push_str("$$"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    #[test]
+    fn test_no_deps() {
+        assert_eq!(make_depfile("a", ["a.txtpp"]), "a: \\\n  a.txtpp\n");
+    }
+
+    #[test]
+    fn test_deps() {
+        assert_eq!(
+            make_depfile("a", ["a.txtpp", "b", "c/d"]),
+            "a: \\\n  a.txtpp \\\n  b \\\n  c/d\n\nb:\n\nc/d:\n"
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_depfile_path("a b#$c"), "a\\ b\\#$$c");
+    }
+}
@@ -0,0 +1,270 @@
+use crate::core::{Event, FileStatus, Mode, Observer};
+use crate::error::{printables, PpError, PpErrorKind};
+use derivative::Derivative;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// [`Observer`] that writes each event as one line of JSON, like `cargo --message-format=json`
+///
+/// Every message is an object with a `reason` field:
+/// - `started`: `base` and `mode` of the run
+/// - `processing`: a file is being processed, with its `input` and `output`
+/// - `directive`: a directive was executed, with its `file`, `line` and `directive`
+/// - `processed`: a file is done, with its `input`, `output`, `status` and `duration_ms`
+/// - `skipped`: a file is skipped because it depends on a failed file
+/// - `verify-mismatch`: an output is not up-to-date, with `kind`, `file`, `line` and `printables`
+/// - `diff`: the unified `diff` of an output `file` that is not up-to-date
+/// - `error`: a file failed, with `kind`, `file`, `line` and `printables` from the error report
+/// - `warning`: a `message` that doesn't stop the run
+/// - `finished`: the run is done, with `success`, the number of `files`, `duration_ms`,
+///   the `plan` in [`Mode::Plan`], and the `orphan_files` when cleaning orphans
+///
+/// Values of `mode`, `status` and `kind` are kebab-case, like `in-memory-build`.
+/// Other events are not written.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct JsonObserver {
+    #[derivative(Debug = "ignore")]
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonObserver {
+    /// Create an observer that writes to stdout
+    pub fn stdout() -> Self {
+        Self::new(Box::new(io::stdout()))
+    }
+
+    /// Create an observer that writes to `out`
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+
+    fn write(&self, message: &Message) -> io::Result<()> {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        serde_json::to_writer(&mut *out, message)?;
+        writeln!(out)?;
+        out.flush()
+    }
+}
+
+impl Observer for JsonObserver {
+    fn notify(&self, event: &Event) {
+        let message = match event {
+            Event::Started { base, mode, .. } => Message::Started { base, mode },
+            Event::FileStarted { input, output } => Message::Processing { input, output },
+            Event::DirectiveExecuted {
+                file,
+                line,
+                directive,
+            } => Message::Directive {
+                file,
+                line: *line,
+                directive,
+            },
+            Event::FileFinished { file } => Message::Processed {
+                input: &file.input,
+                output: &file.output,
+                status: file.status,
+                duration_ms: file.duration.as_millis(),
+            },
+            Event::FileFailed { error, .. } => {
+                Message::from_error(Some(error.current_context()), printables(error))
+            }
+            Event::FileSkipped { input } => Message::Skipped { input },
+            Event::Diff { diff } => Message::Diff {
+                file: &diff.file,
+                diff: &diff.diff,
+            },
+            Event::Warning { message } => Message::Warning { message },
+            Event::Error { error } => {
+                // with keep_going, the failures are already reported as they happen
+                if error.current_context().failures.is_empty() {
+                    let message =
+                        Message::from_error(error.downcast_ref::<PpError>(), printables(error));
+                    let _ = self.write(&message);
+                }
+                Message::Finished {
+                    success: false,
+                    files: 0,
+                    duration_ms: 0,
+                    plan: None,
+                    orphan_files: &[],
+                }
+            }
+            Event::Finished { report, .. } => Message::Finished {
+                success: true,
+                files: report.files.len(),
+                duration_ms: report.duration.as_millis(),
+                plan: report.plan.as_deref(),
+                orphan_files: &report.orphan_files,
+            },
+            _ => return,
+        };
+        let _ = self.write(&message);
+    }
+}
+
+/// One line of output from [`JsonObserver`]
+#[derive(Debug, Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum Message<'a> {
+    Started {
+        base: &'a Path,
+        mode: &'a Mode,
+    },
+    Processing {
+        input: &'a Path,
+        output: &'a Path,
+    },
+    Directive {
+        file: &'a str,
+        line: usize,
+        directive: &'a str,
+    },
+    Processed {
+        input: &'a Path,
+        output: &'a Path,
+        status: FileStatus,
+        duration_ms: u128,
+    },
+    Skipped {
+        input: &'a Path,
+    },
+    VerifyMismatch(ErrorMessage<'a>),
+    Diff {
+        file: &'a str,
+        diff: &'a str,
+    },
+    Error(ErrorMessage<'a>),
+    Warning {
+        message: &'a str,
+    },
+    Finished {
+        success: bool,
+        files: usize,
+        duration_ms: u128,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        plan: Option<&'a str>,
+        #[serde(skip_serializing_if = "<[PathBuf]>::is_empty")]
+        orphan_files: &'a [PathBuf],
+    },
+}
+
+impl<'a> Message<'a> {
+    fn from_error(error: Option<&'a PpError>, printables: Vec<String>) -> Self {
+        let message = ErrorMessage {
+            kind: error.map(|e| &e.kind),
+            file: error.map(|e| e.file.as_str()),
+            line: error.map(|e| e.line),
+            printables,
+        };
+        match message.kind {
+            Some(PpErrorKind::VerifyOutput) => Self::VerifyMismatch(message),
+            _ => Self::Error(message),
+        }
+    }
+}
+
+/// Fields of an error message. They are `null` if the error is not from a file
+#[derive(Debug, Serialize)]
+struct ErrorMessage<'a> {
+    kind: Option<&'a PpErrorKind>,
+    file: Option<&'a str>,
+    line: Option<usize>,
+    printables: Vec<String>,
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+    use crate::core::FileReport;
+    use error_stack::Report;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn lines(buf: &SharedBuf) -> Vec<serde_json::Value> {
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        out.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_processed() {
+        let buf = SharedBuf::default();
+        let observer = JsonObserver::new(Box::new(buf.clone()));
+        let file = FileReport {
+            input: "a.txt.txtpp".into(),
+            output: "a.txt".into(),
+            status: FileStatus::Written,
+            duration: Duration::from_millis(3),
+            deps: vec![],
+            temps: vec![],
+        };
+        observer.notify(&Event::FileFinished { file: &file });
+        let lines = lines(&buf);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["reason"], "processed");
+        assert_eq!(lines[0]["output"], "a.txt");
+        assert_eq!(lines[0]["status"], "written");
+        assert_eq!(lines[0]["duration_ms"], 3);
+    }
+
+    #[test]
+    fn test_error() {
+        let buf = SharedBuf::default();
+        let observer = JsonObserver::new(Box::new(buf.clone()));
+        let error = Report::new(PpError {
+            kind: PpErrorKind::Directive,
+            file: "a.txt.txtpp".to_string(),
+            line: 2,
+        })
+        .attach_printable("first")
+        .attach_printable("second");
+        observer.notify(&Event::FileFailed {
+            input: Path::new("a.txt.txtpp"),
+            error: &error,
+        });
+        let lines = lines(&buf);
+        assert_eq!(lines[0]["reason"], "error");
+        assert_eq!(lines[0]["kind"], "directive");
+        assert_eq!(lines[0]["file"], "a.txt.txtpp");
+        assert_eq!(lines[0]["line"], 2);
+        assert_eq!(
+            lines[0]["printables"],
+            serde_json::json!(["second", "first"])
+        );
+    }
+
+    #[test]
+    fn test_verify_mismatch() {
+        let buf = SharedBuf::default();
+        let observer = JsonObserver::new(Box::new(buf.clone()));
+        let error = Report::new(PpError {
+            kind: PpErrorKind::VerifyOutput,
+            file: "a.txt.txtpp".to_string(),
+            line: 1,
+        });
+        observer.notify(&Event::FileFailed {
+            input: Path::new("a.txt.txtpp"),
+            error: &error,
+        });
+        assert_eq!(lines(&buf)[0]["reason"], "verify-mismatch");
+    }
+}
@@ -0,0 +1,124 @@
+/// Default format of line markers, which is the `#line` directive of C-family languages
+pub const DEFAULT_LINE_MARKER: &str = "#line {line} \"{file}\"";
+
+/// Format of the line markers written to outputs of a file type, like `#line {line} "{file}"`
+///
+/// `{line}` is replaced with the line number and `{file}` with the path of the file
+/// the next line is from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineMarker {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Line,
+    File,
+}
+
+impl LineMarker {
+    pub fn new(format: &str) -> Self {
+        let mut parts = vec![];
+        let mut rest = format;
+        while let Some(i) = rest.find('{') {
+            let (part, len) = if rest[i..].starts_with("{line}") {
+                (Part::Line, "{line}".len())
+            } else if rest[i..].starts_with("{file}") {
+                (Part::File, "{file}".len())
+            } else {
+                push_literal(&mut parts, &rest[..=i]);
+                rest = &rest[i + 1..];
+                continue;
+            };
+            push_literal(&mut parts, &rest[..i]);
+            parts.push(part);
+            rest = &rest[i + len..];
+        }
+        push_literal(&mut parts, rest);
+        Self { parts }
+    }
+
+    /// Make the marker for the line of the file, without line ending
+    pub fn format(&self, line: usize, file: &str) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(s) => s.clone(),
+                Part::Line => line.to_string(),
+                Part::File => file.to_string(),
+            })
+            .collect()
+    }
+
+    /// If the line (without line ending) is a marker in this format
+    pub fn is_marker(&self, line: &str) -> bool {
+        matches(&self.parts, line)
+    }
+
+    /// Remove the lines that are markers from the content
+    pub fn strip(&self, content: &str) -> String {
+        content
+            .split_inclusive('\n')
+            .filter(|line| !self.is_marker(line.trim_end_matches(['\r', '\n'])))
+            .collect()
+    }
+}
+
+fn push_literal(parts: &mut Vec<Part>, s: &str) {
+    if s.is_empty() {
+        return;
+    }
+    match parts.last_mut() {
+        Some(Part::Literal(last)) => last.push_str(s),
+        _ => parts.push(Part::Literal(s.to_string())),
+    }
+}
+
+fn matches(parts: &[Part], s: &str) -> bool {
+    match parts.split_first() {
+        None => s.is_empty(),
+        Some((Part::Literal(literal), rest)) => s
+            .strip_prefix(literal.as_str())
+            .is_some_and(|s| matches(rest, s)),
+        Some((Part::Line, rest)) => {
+            let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            digits > 0 && matches(rest, &s[digits..])
+        }
+        Some((Part::File, rest)) => (0..=s.len())
+            .filter(|i| s.is_char_boundary(*i))
+            .any(|i| matches(rest, &s[i..])),
+    }
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let marker = LineMarker::new(DEFAULT_LINE_MARKER);
+        assert_eq!(marker.format(3, "a.c.txtpp"), "#line 3 \"a.c.txtpp\"");
+        let marker = LineMarker::new("// {line} {unknown}");
+        assert_eq!(marker.format(3, "a"), "// 3 {unknown}");
+    }
+
+    #[test]
+    fn test_is_marker() {
+        let marker = LineMarker::new(DEFAULT_LINE_MARKER);
+        assert!(marker.is_marker("#line 12 \"sub/a b.h\""));
+        assert!(!marker.is_marker("#line x \"a.h\""));
+        assert!(!marker.is_marker("#line 12 \"a.h\" extra"));
+        assert!(!marker.is_marker("int x;"));
+        let marker = LineMarker::new("#line {line}");
+        assert!(marker.is_marker("#line 1"));
+        assert!(!marker.is_marker("#line 1 \"a.h\""));
+    }
+
+    #[test]
+    fn test_strip() {
+        let marker = LineMarker::new(DEFAULT_LINE_MARKER);
+        let content = "#line 1 \"a.c.txtpp\"\r\nint x;\r\n#line 5 \"b.h\"\r\nint y;";
+        assert_eq!(marker.strip(content), "int x;\r\nint y;");
+    }
+}
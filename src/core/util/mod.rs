@@ -1,8 +1,24 @@
+mod banner;
+pub use banner::*;
 mod dependency;
 pub use dependency::*;
+mod dep_index;
+pub use dep_index::*;
+mod dep_graph;
+pub use dep_graph::*;
+mod depfile;
+pub use depfile::*;
+mod json;
+pub use json::*;
+mod line_marker;
+pub use line_marker::*;
 mod progress;
 pub use progress::*;
+mod source_map;
+pub use source_map::*;
 mod string;
 pub use string::*;
+mod terminal;
+pub use terminal::*;
 mod tag_state;
 pub use tag_state::*;
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+
+/// Version of the source map format
+const SOURCE_MAP_VERSION: u32 = 1;
+
+/// Map from line ranges of an output file to where they are from
+///
+/// This is written as JSON next to the output with [`Config::source_map`](crate::Config::source_map) enabled.
+/// Paths are relative to the base directory. Line numbers are 1-based.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceMap {
+    /// Version of the format, which is 1
+    pub version: u32,
+    /// The output file
+    pub file: String,
+    /// The `.txtpp` file
+    pub source: String,
+    /// The line ranges in the order they are in the output
+    pub mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    /// Serialize the map as JSON
+    pub fn to_json(&self) -> String {
+        let mut out = serde_json::to_string_pretty(self).unwrap_or_default();
+        out.push('\n');
+        out
+    }
+}
+
+/// Range of lines in the output and their origin
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mapping {
+    /// The first line in the output
+    pub start: usize,
+    /// The last line in the output, inclusive
+    pub end: usize,
+    #[serde(flatten)]
+    pub origin: Origin,
+}
+
+/// Where lines in the output are from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Origin {
+    /// Lines of the `.txtpp` file, starting from `line`
+    Source { line: usize },
+    /// Lines of the file in an `include` directive, starting from `line` of that file
+    Include { file: String, line: usize },
+    /// Output of the command of the `run` directive at `line`
+    Run { line: usize, command: String },
+    /// Content of the `write` directive at `line`
+    Write { line: usize },
+}
+
+/// Builds a [`SourceMap`] from the output as it's written
+#[derive(Debug)]
+pub struct SourceMapBuilder {
+    mappings: Vec<Mapping>,
+    /// The output line the next text is written to
+    line: usize,
+}
+
+impl Default for SourceMapBuilder {
+    fn default() -> Self {
+        Self {
+            mappings: vec![],
+            line: 1,
+        }
+    }
+}
+
+impl SourceMapBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record text written to the output. Text without an origin (like line endings) only moves the line
+    ///
+    /// If the text starts in the middle of a line, that line is in the mappings of both origins.
+    pub fn add(&mut self, text: &str, origin: Option<Origin>) {
+        let newlines = text.matches('\n').count();
+        let start = self.line;
+        self.line += newlines;
+        let origin = match origin {
+            Some(origin) if !text.is_empty() => origin,
+            _ => return,
+        };
+        let end = if text.ends_with('\n') {
+            self.line - 1
+        } else {
+            self.line
+        };
+        // consecutive lines of the source are one mapping
+        if let (Some(last), Origin::Source { line }) = (self.mappings.last_mut(), &origin) {
+            if let Origin::Source { line: last_line } = last.origin {
+                if start == end
+                    && start == last.end + 1
+                    && *line == last_line + (last.end - last.start) + 1
+                {
+                    last.end = end;
+                    return;
+                }
+            }
+        }
+        self.mappings.push(Mapping { start, end, origin });
+    }
+
+    /// Get the source map of the output file
+    pub fn finish(self, file: String, source: String) -> SourceMap {
+        SourceMap {
+            version: SOURCE_MAP_VERSION,
+            file,
+            source,
+            mappings: self.mappings,
+        }
+    }
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    fn source(line: usize) -> Option<Origin> {
+        Some(Origin::Source { line })
+    }
+
+    #[test]
+    fn test_source_lines_are_merged() {
+        let mut builder = SourceMapBuilder::new();
+        builder.add("a", source(1));
+        builder.add("\n", None);
+        builder.add("b", source(2));
+        builder.add("\n", None);
+        builder.add("c", source(4));
+        let map = builder.finish("a".to_string(), "a.txtpp".to_string());
+        assert_eq!(
+            map.mappings,
+            vec![
+                Mapping {
+                    start: 1,
+                    end: 2,
+                    origin: Origin::Source { line: 1 }
+                },
+                Mapping {
+                    start: 3,
+                    end: 3,
+                    origin: Origin::Source { line: 4 }
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directive_output() {
+        let mut builder = SourceMapBuilder::new();
+        builder.add("a", source(1));
+        builder.add("\n", None);
+        builder.add(
+            "x\ny\n",
+            Some(Origin::Run {
+                line: 2,
+                command: "echo".to_string(),
+            }),
+        );
+        builder.add("b", source(3));
+        let map = builder.finish("a".to_string(), "a.txtpp".to_string());
+        assert_eq!(map.mappings.len(), 3);
+        assert_eq!((map.mappings[1].start, map.mappings[1].end), (2, 3));
+        assert_eq!((map.mappings[2].start, map.mappings[2].end), (4, 4));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut builder = SourceMapBuilder::new();
+        builder.add(
+            "x",
+            Some(Origin::Include {
+                file: "b.txt".to_string(),
+                line: 1,
+            }),
+        );
+        let json = builder
+            .finish("a".to_string(), "a.txtpp".to_string())
+            .to_json();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["version"], 1);
+        assert_eq!(
+            value["mappings"][0],
+            serde_json::json!({"start": 1, "end": 1, "kind": "include", "file": "b.txt", "line": 1})
+        );
+    }
+}
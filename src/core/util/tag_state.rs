@@ -73,7 +73,7 @@ impl TagState {
             .filter_map(|(k, v)| output.find(k).map(|i| (i, k, v)))
             .collect::<Vec<_>>();
         // sort by index
-        to_inject.sort_by(|a, b| a.0.cmp(&b.0));
+        to_inject.sort_by_key(|a| a.0);
         let mut injected_output = String::new();
         let mut last_end = 0;
         let mut to_remove = vec![];
@@ -93,6 +93,11 @@ impl TagState {
         injected_output
     }
 
+    /// If a tag is waiting for the output of the next directive
+    pub fn is_listening(&self) -> bool {
+        self.listening.is_some()
+    }
+
     pub fn has_tags(&self) -> bool {
         self.listening.is_some() || !self.stored.is_empty()
     }
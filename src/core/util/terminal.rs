@@ -0,0 +1,217 @@
+use crate::core::{verbs, Event, FileStatus, Mode, Observer, Verbosity};
+use crate::fs::normalize_path;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+/// The default [`Observer`], which prints colored status lines and a progress counter to stderr
+#[derive(Debug)]
+pub struct TerminalObserver {
+    verbosity: Verbosity,
+    state: Mutex<TerminalState>,
+}
+
+#[derive(Debug)]
+struct TerminalState {
+    out: StandardStream,
+    /// The base directory to display paths relative to
+    base: PathBuf,
+    mode: Mode,
+    done_count: usize,
+    total_count: usize,
+    last_update: Instant,
+}
+
+impl TerminalObserver {
+    pub fn new(verbosity: Verbosity) -> Self {
+        Self {
+            verbosity,
+            state: Mutex::new(TerminalState {
+                out: StandardStream::stderr(ColorChoice::Always),
+                base: PathBuf::new(),
+                mode: Mode::Build,
+                done_count: 0,
+                total_count: 0,
+                last_update: Instant::now(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TerminalState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn print(&self, event: &Event) -> Result<(), Box<dyn Error>> {
+        let mut state = self.lock();
+        match event {
+            Event::Started {
+                base,
+                mode,
+                shell,
+                threads,
+            } => {
+                state.base = base.to_path_buf();
+                state.mode = (*mode).clone();
+                state.done_count = 0;
+                state.total_count = 0;
+                self.print_status(&mut state, verbs::USING, shell, Color::Yellow, true)?;
+                let threads = format!("{threads} thread(s)");
+                self.print_status(&mut state, verbs::USING, &threads, Color::Yellow, true)?;
+            }
+            Event::DirScanned { dir, .. } => {
+                let dir = state.display(dir);
+                self.print_status(&mut state, verbs::SCANNED, &dir, Color::Yellow, true)?;
+            }
+            Event::FileStarted { output, .. } => {
+                let verb = state.mode.processing_verb();
+                let output = state.display(output);
+                self.print_status(&mut state, verb, &output, Color::Yellow, true)?;
+            }
+            Event::DirectiveExecuted { .. } => {}
+            Event::FileFinished { file } => {
+                let (verb, verbose) = match file.status {
+                    FileStatus::Fresh => (verbs::FRESH, true),
+                    FileStatus::Planned => (verbs::PLANNED, false),
+                    _ => (state.mode.processed_verb(), false),
+                };
+                let output = state.display(&file.output);
+                self.print_status(&mut state, verb, &output, Color::Green, verbose)?;
+            }
+            Event::FileFailed { input, .. } => {
+                let input = state.display(input);
+                self.print_status(&mut state, verbs::FAILED, &input, Color::Red, false)?;
+            }
+            Event::FileSkipped { input } => {
+                let input = state.display(input);
+                self.print_status(&mut state, verbs::SKIPPED, &input, Color::Yellow, false)?;
+            }
+            Event::Diff { diff } => {
+                // the diff is part of the error, so it's printed regardless of the verbosity
+                print_diff(&mut state.out, &diff.diff)?;
+            }
+            Event::Progress { done, total } => {
+                state.done_count = *done;
+                state.total_count = *total;
+                if self.verbosity != Verbosity::Quiet
+                    && state.last_update.elapsed() > Duration::from_millis(100)
+                {
+                    state.update_progress()?;
+                }
+            }
+            Event::Warning { message } => {
+                self.print_status(&mut state, verbs::WARNING, message, Color::Yellow, false)?;
+            }
+            Event::Error { .. } => {
+                self.print_status(&mut state, verbs::FAILED, "", Color::Red, false)?;
+            }
+            Event::Finished { report, paths } => {
+                let paths = format!("{paths} path(s)");
+                self.print_status(&mut state, verbs::SCANNED, &paths, Color::Yellow, true)?;
+                let files = format!(
+                    "{} file(s) in {:.2}s",
+                    report.files.len(),
+                    report.duration.as_secs_f32()
+                );
+                self.print_status(&mut state, verbs::DONE, &files, Color::Green, false)?;
+            }
+            Event::Watching { paths } => {
+                let paths = format!("{paths} path(s)");
+                self.print_status(&mut state, verbs::WATCHING, &paths, Color::Yellow, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn print_status(
+        &self,
+        state: &mut TerminalState,
+        status: &str,
+        message: &str,
+        color: Color,
+        verbose: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.verbosity == Verbosity::Quiet {
+            return Ok(());
+        }
+        if verbose && self.verbosity != Verbosity::Verbose {
+            return Ok(());
+        }
+        let out = &mut state.out;
+        out.reset()?;
+        out.set_color(ColorSpec::new().set_bold(true).set_fg(Some(color)))?;
+        write!(out, "{:>12}", status)?;
+        out.reset()?;
+        // write the message then cleared to the end of line
+        writeln!(out, " {}{esc}[0K", message, esc = 27 as char)?;
+        if state.done_count < state.total_count {
+            state.update_progress()?;
+        }
+        Ok(())
+    }
+}
+
+impl Observer for TerminalObserver {
+    fn notify(&self, event: &Event) {
+        let _ = self.print(event);
+    }
+}
+
+impl TerminalState {
+    /// Display the path relative to the base directory, or `.` for the base directory itself
+    fn display(&self, p: &Path) -> String {
+        let p = p.strip_prefix(&self.base).unwrap_or(p);
+        if p.as_os_str().is_empty() {
+            return ".".to_string();
+        }
+        normalize_path(&p.display().to_string()).to_string()
+    }
+
+    fn update_progress(&mut self) -> Result<(), Box<dyn Error>> {
+        self.out
+            .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Yellow)))?;
+        let progress = format!("{}/{}", self.done_count, self.total_count);
+        write!(self.out, "{:>12}", verbs::SCANNED)?;
+        self.out.reset()?;
+        write!(self.out, " {}\r", progress)?;
+        self.last_update = Instant::now();
+
+        Ok(())
+    }
+}
+
+/// Print a unified diff with colors
+fn print_diff(out: &mut StandardStream, diff: &str) -> Result<(), Box<dyn Error>> {
+    out.reset()?;
+    // clear the progress line
+    write!(out, "{esc}[0K", esc = 27 as char)?;
+    for line in diff.lines() {
+        let color = if line.starts_with("+++") || line.starts_with("---") {
+            Some(Color::White)
+        } else if line.starts_with('+') {
+            Some(Color::Green)
+        } else if line.starts_with('-') {
+            Some(Color::Red)
+        } else if line.starts_with("@@") {
+            Some(Color::Cyan)
+        } else {
+            None
+        };
+        match color {
+            Some(color) => {
+                out.set_color(
+                    ColorSpec::new()
+                        .set_bold(color == Color::White)
+                        .set_fg(Some(color)),
+                )?;
+                write!(out, "{line}")?;
+                out.reset()?;
+                writeln!(out)?;
+            }
+            None => writeln!(out, "{line}")?,
+        }
+    }
+    Ok(())
+}
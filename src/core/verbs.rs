@@ -1,12 +1,17 @@
 pub const USING: &str = "Using";
 pub const PROCESSING: &str = "Processing";
 pub const PROCESSED: &str = "Processed";
-pub const SCANNING: &str = "Scanning";
+pub const FRESH: &str = "Fresh";
 pub const CLEANING: &str = "Cleaning";
 pub const CLEANED: &str = "Cleaned";
 pub const VERIFYING: &str = "Verifying";
 pub const VERIFIED: &str = "Verified";
+pub const WATCHING: &str = "Watching";
+pub const PLANNING: &str = "Planning";
+pub const PLANNED: &str = "Planned";
 
 pub const SCANNED: &str = "Scanned";
 pub const FAILED: &str = "Failed";
+pub const SKIPPED: &str = "Skipped";
+pub const WARNING: &str = "Warning";
 pub const DONE: &str = "Finished";
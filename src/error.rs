@@ -1,18 +1,36 @@
 //! Error types
 
+use error_stack::{AttachmentKind, FrameKind, Report};
+use serde::Serialize;
 use std::error;
 use std::fmt;
 use std::path::Path;
 
 /// Top level error
-#[derive(Debug)]
-pub struct TxtppError;
+///
+/// When running with [`Config::keep_going`](crate::Config::keep_going), this carries the files that failed
+/// and the files that were skipped because of them. Otherwise, the run stops at the first error and the lists are empty.
+#[derive(Debug, Default)]
+pub struct TxtppError {
+    /// Files that failed to process
+    pub failures: Vec<Failure>,
+    /// Files that were not processed because they depend on a failed file, relative to the base directory
+    pub skipped: Vec<String>,
+}
 
 impl fmt::Display for TxtppError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.failures.is_empty() {
+            return write!(
+                f,
+                "Txtpp was unsuccessful! There might be error traces below."
+            );
+        }
         write!(
             f,
-            "Txtpp was unsuccessful! There might be error traces below."
+            "Txtpp was unsuccessful! {} file(s) failed and {} file(s) were skipped. There might be error traces below.",
+            self.failures.len(),
+            self.skipped.len()
         )
     }
 }
@@ -20,19 +38,21 @@ impl fmt::Display for TxtppError {
 impl error::Error for TxtppError {}
 
 /// Preprocessor error
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PpError {
     pub kind: PpErrorKind,
     pub file: String,
     pub line: usize,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum PpErrorKind {
     OpenFile,
     ReadFile,
     WriteFile,
     DeleteFile,
     VerifyOutput,
+    EditedOutput,
     Directive,
     Other,
 }
@@ -46,6 +66,9 @@ impl fmt::Display for PpError {
             PpErrorKind::WriteFile => write!(f, "Could not write file {source}"),
             PpErrorKind::DeleteFile => write!(f, "Could not remove file {source}"),
             PpErrorKind::VerifyOutput => write!(f, "Output file not verified {source}"),
+            PpErrorKind::EditedOutput => {
+                write!(f, "Output file was changed since the last run {source}")
+            }
             PpErrorKind::Directive => write!(f, "Error executing directive {source}"),
             PpErrorKind::Other => write!(f, "Internal error {source}"),
         }
@@ -54,6 +77,35 @@ impl fmt::Display for PpError {
 
 impl error::Error for PpError {}
 
+/// A file that failed to process, collected when running with [`Config::keep_going`](crate::Config::keep_going)
+#[derive(Debug, Clone)]
+pub struct Failure {
+    /// The error
+    pub error: PpError,
+    /// Messages attached to the error, outermost first
+    pub printables: Vec<String>,
+}
+
+impl From<&Report<PpError>> for Failure {
+    fn from(report: &Report<PpError>) -> Self {
+        Self {
+            error: report.current_context().clone(),
+            printables: printables(report),
+        }
+    }
+}
+
+/// Get the messages attached to the report, outermost first
+pub(crate) fn printables<C>(report: &Report<C>) -> Vec<String> {
+    report
+        .frames()
+        .filter_map(|frame| match frame.kind() {
+            FrameKind::Attachment(AttachmentKind::Printable(p)) => Some(p.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
 use crate::fs::normalize_path;
 
 /// Error related to paths
@@ -81,3 +133,15 @@ impl fmt::Display for PathError {
 }
 
 impl error::Error for PathError {}
+
+/// Difference between an output file and its fresh output
+///
+/// This is attached to the [`PpErrorKind::VerifyOutput`] error report
+/// when verifying with [`Config::verify_diff`](crate::Config::verify_diff) enabled.
+#[derive(Debug, Clone)]
+pub struct VerifyDiff {
+    /// The output file, relative to the base directory
+    pub file: String,
+    /// Unified diff from the existing output file to the fresh output
+    pub diff: String,
+}
@@ -0,0 +1,491 @@
+//! File system abstraction, so txtpp can run over trees that are not on disk
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// File system operations used by txtpp
+///
+/// Every file that txtpp reads or writes goes through this trait, including `.txtpp` files, outputs,
+/// included files, temporary files, the incremental build cache and depfiles.
+/// Commands in `run` directives are still executed by the shell in the real file system.
+///
+/// Use [`RealFs`] for the real file system, and [`MemoryFs`] for an in-memory tree.
+pub trait FileSystem: fmt::Debug + Send + Sync {
+    /// Check if the path is a file
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// Check if the path is a directory
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Check if the path exists
+    fn exists(&self, path: &Path) -> bool {
+        self.is_file(path) || self.is_dir(path)
+    }
+
+    /// Check if the path itself is a symbolic link. The default is `false` for file systems without links
+    fn is_symlink(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// Get the absolute path with `.` and `..` resolved. The path must exist
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Get the paths of the entries in a directory
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Open a file for reading
+    fn open(&self, path: &Path) -> io::Result<Box<dyn BufRead>>;
+
+    /// Read the whole file as a string
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        let mut s = String::new();
+        self.open(path)?.read_to_string(&mut s)?;
+        Ok(s)
+    }
+
+    /// Get the size of a file in bytes
+    fn file_len(&self, path: &Path) -> io::Result<u64>;
+
+    /// Get the last modification time of a file
+    fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+
+    /// Create or truncate a file for writing. The content is written when the writer is flushed
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write>>;
+
+    /// Write the whole file, creating or truncating it
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    /// Remove a file
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Rename a file, replacing the file at `to` if it exists.
+    ///
+    /// This is used to replace outputs atomically, so it should be atomic if the file system supports it.
+    /// The permissions of the replaced file should be kept
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Create a directory and all of its parents
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+}
+
+/// The real file system, using [`std::fs`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl FileSystem for RealFs {
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        path.is_symlink()
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        path.read_dir()?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn BufRead>> {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        fs::metadata(path)?.modified()
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if let Ok(metadata) = fs::metadata(to) {
+            fs::set_permissions(from, metadata.permissions())?;
+        }
+        fs::rename(from, to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+}
+
+/// An in-memory file system
+///
+/// Clones share the same tree, so the outputs can be read from a clone after running txtpp.
+/// Relative paths are relative to the root (`/`).
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use txtpp::fs::MemoryFs;
+/// use txtpp::{Config, Txtpp, Verbosity};
+///
+/// let fs = MemoryFs::new();
+/// fs.insert("/project/foo.txt.txtpp", "TXTPP#include bar.txt\n");
+/// fs.insert("/project/bar.txt", "hello");
+///
+/// let config = Config {
+///     base_dir: "/project".into(),
+///     verbosity: Verbosity::Quiet,
+///     fs: Arc::new(fs.clone()),
+///     ..Default::default()
+/// };
+/// Txtpp::run(config).unwrap();
+/// assert_eq!(fs.get_string("/project/foo.txt").unwrap(), "hello\n");
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryFs {
+    tree: Arc<Mutex<MemoryTree>>,
+}
+
+#[derive(Default)]
+struct MemoryTree {
+    /// Files and their content and modification time
+    files: BTreeMap<PathBuf, (Vec<u8>, SystemTime)>,
+    /// Directories, not including the root
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl MemoryFs {
+    /// Create an empty file system
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file, creating its parent directories
+    pub fn insert<P, C>(&self, path: P, contents: C)
+    where
+        P: AsRef<Path>,
+        C: Into<Vec<u8>>,
+    {
+        let path = normalize(path.as_ref());
+        let mut tree = self.lock();
+        if let Some(parent) = path.parent() {
+            tree.add_dirs(parent);
+        }
+        tree.files
+            .insert(path, (contents.into(), SystemTime::now()));
+    }
+
+    /// Get the content of a file
+    pub fn get<P>(&self, path: P) -> Option<Vec<u8>>
+    where
+        P: AsRef<Path>,
+    {
+        let path = normalize(path.as_ref());
+        self.lock().files.get(&path).map(|(c, _)| c.clone())
+    }
+
+    /// Get the content of a file as a string. `None` is returned if the file doesn't exist or is not valid UTF-8
+    pub fn get_string<P>(&self, path: P) -> Option<String>
+    where
+        P: AsRef<Path>,
+    {
+        self.get(path).and_then(|c| String::from_utf8(c).ok())
+    }
+
+    /// Get the paths of all files
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.lock().files.keys().cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryTree> {
+        // the tree is always valid even if another thread panicked while holding the lock
+        self.tree.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_file(&self, path: &Path, contents: Vec<u8>) -> io::Result<()> {
+        let path = normalize(path);
+        let mut tree = self.lock();
+        if tree.is_dir(&path) {
+            return Err(io::Error::other("cannot write to a directory"));
+        }
+        match path.parent() {
+            Some(parent) if !tree.is_dir(parent) => Err(not_found()),
+            _ => {
+                tree.files.insert(path, (contents, SystemTime::now()));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl MemoryTree {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.parent().is_none() || self.dirs.contains(path)
+    }
+
+    fn add_dirs(&mut self, path: &Path) {
+        for dir in path.ancestors() {
+            if dir.parent().is_none() {
+                break;
+            }
+            self.dirs.insert(dir.to_path_buf());
+        }
+    }
+}
+
+impl fmt::Debug for MemoryFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryFs")
+            .field("files", &self.lock().files.len())
+            .finish()
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn is_file(&self, path: &Path) -> bool {
+        self.lock().files.contains_key(&normalize(path))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.lock().is_dir(&normalize(path))
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        if self.exists(path) {
+            Ok(normalize(path))
+        } else {
+            Err(not_found())
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let path = normalize(path);
+        let tree = self.lock();
+        if !tree.is_dir(&path) {
+            return Err(not_found());
+        }
+        let files = tree.files.keys();
+        let dirs = tree.dirs.iter();
+        Ok(files
+            .chain(dirs)
+            .filter(|p| p.parent() == Some(path.as_path()))
+            .cloned()
+            .collect())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn BufRead>> {
+        let contents = self.get(path).ok_or_else(not_found)?;
+        Ok(Box::new(Cursor::new(contents)))
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        let path = normalize(path);
+        match self.lock().files.get(&path) {
+            Some((contents, _)) => Ok(contents.len() as u64),
+            None => Err(not_found()),
+        }
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        let path = normalize(path);
+        match self.lock().files.get(&path) {
+            Some((_, modified)) => Ok(*modified),
+            None => Err(not_found()),
+        }
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+        self.write_file(path, vec![])?;
+        Ok(Box::new(MemoryWriter {
+            fs: self.clone(),
+            path: path.to_path_buf(),
+            buf: vec![],
+        }))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.write_file(path, contents.to_vec())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.lock().files.remove(&normalize(path)) {
+            Some(_) => Ok(()),
+            None => Err(not_found()),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        let mut tree = self.lock();
+        if tree.is_dir(&to) {
+            return Err(io::Error::other("cannot replace a directory"));
+        }
+        match to.parent() {
+            Some(parent) if !tree.is_dir(parent) => return Err(not_found()),
+            _ => {}
+        }
+        let (contents, _) = tree.files.remove(&from).ok_or_else(not_found)?;
+        tree.files.insert(to, (contents, SystemTime::now()));
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut tree = self.lock();
+        if tree.files.contains_key(&path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a file exists at the path",
+            ));
+        }
+        tree.add_dirs(&path);
+        Ok(())
+    }
+}
+
+/// Writer for a file in [`MemoryFs`], which updates the file when flushed or dropped
+struct MemoryWriter {
+    fs: MemoryFs,
+    path: PathBuf,
+    buf: Vec<u8>,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.fs.write_file(&self.path, self.buf.clone())
+    }
+}
+
+impl Drop for MemoryWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "path does not exist")
+}
+
+/// Make the path absolute from the root and resolve `.` and `..`
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Prefix(prefix) => out = PathBuf::from(prefix.as_os_str()),
+            Component::RootDir => out.push(Component::RootDir),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::Normal(c) => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("a/./b/../c")), PathBuf::from("/a/c"));
+        assert_eq!(normalize(Path::new("/a/b")), PathBuf::from("/a/b"));
+        assert_eq!(normalize(Path::new("/..")), PathBuf::from("/"));
+    }
+
+    #[test]
+    fn test_memory_fs() {
+        let fs = MemoryFs::new();
+        fs.insert("/a/b/c.txt", "hello");
+        assert!(fs.is_file(Path::new("/a/b/c.txt")));
+        assert!(fs.is_dir(Path::new("/a/b")));
+        assert!(fs.is_dir(Path::new("/a")));
+        assert!(fs.is_dir(Path::new("/")));
+        assert!(!fs.exists(Path::new("/a/c")));
+        assert_eq!(
+            fs.read_dir(Path::new("/a")).unwrap(),
+            vec![PathBuf::from("/a/b")]
+        );
+        assert_eq!(
+            fs.read_to_string(Path::new("a/b/../b/c.txt")).unwrap(),
+            "hello"
+        );
+        assert_eq!(fs.file_len(Path::new("/a/b/c.txt")).unwrap(), 5);
+        assert_eq!(
+            fs.canonicalize(Path::new("/a/./b")).unwrap(),
+            PathBuf::from("/a/b")
+        );
+        assert!(fs.canonicalize(Path::new("/x")).is_err());
+    }
+
+    #[test]
+    fn test_memory_fs_write() {
+        let fs = MemoryFs::new();
+        // parent must exist
+        assert!(fs.write(Path::new("/a/b.txt"), b"x").is_err());
+        fs.create_dir_all(Path::new("/a")).unwrap();
+        fs.write(Path::new("/a/b.txt"), b"x").unwrap();
+        assert_eq!(fs.get("/a/b.txt").unwrap(), b"x");
+        assert!(fs.write(Path::new("/a"), b"x").is_err());
+
+        let mut w = fs.create(Path::new("/a/c.txt")).unwrap();
+        // created empty
+        assert_eq!(fs.get_string("/a/c.txt").unwrap(), "");
+        w.write_all(b"hello").unwrap();
+        w.flush().unwrap();
+        assert_eq!(fs.get_string("/a/c.txt").unwrap(), "hello");
+        drop(w);
+
+        fs.remove_file(Path::new("/a/c.txt")).unwrap();
+        assert!(fs.remove_file(Path::new("/a/c.txt")).is_err());
+        assert_eq!(fs.files(), vec![PathBuf::from("/a/b.txt")]);
+    }
+
+    #[test]
+    fn test_memory_fs_rename() {
+        let fs = MemoryFs::new();
+        fs.insert("/a/b.txt", "b");
+        fs.insert("/a/c.txt", "c");
+        fs.rename(Path::new("/a/b.txt"), Path::new("/a/c.txt"))
+            .unwrap();
+        assert_eq!(fs.get_string("/a/c.txt").unwrap(), "b");
+        assert!(fs.get("/a/b.txt").is_none());
+        assert!(fs
+            .rename(Path::new("/a/b.txt"), Path::new("/a/d.txt"))
+            .is_err());
+        assert!(fs.rename(Path::new("/a/c.txt"), Path::new("/a")).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+//! Content hashing used to detect changes between runs
+
+use crate::error::PathError;
+use crate::fs::{normalize_path, FileSystem};
+use error_stack::{Result, ResultExt};
+use murmur3::murmur3_x64_128;
+use std::io::Read;
+use std::path::Path;
+
+/// Hash the content of a file.
+///
+/// Returns `None` if the file does not exist.
+pub fn hash_file<P>(fs: &dyn FileSystem, p: &P) -> Result<Option<String>, PathError>
+where
+    P: AsRef<Path>,
+{
+    let path = p.as_ref();
+    if !fs.is_file(path) {
+        return Ok(None);
+    }
+    let mut reader = fs
+        .open(path)
+        .change_context_lazy(|| PathError::from(p))
+        .attach_printable_lazy(|| {
+            format!(
+                "could not open file for hashing: `{}`",
+                normalize_path(&path.display().to_string())
+            )
+        })?;
+    hash_reader(&mut reader)
+        .change_context_lazy(|| PathError::from(p))
+        .attach_printable("could not read file for hashing")
+        .map(Some)
+}
+
+fn hash_reader<R>(reader: &mut R) -> std::io::Result<String>
+where
+    R: Read,
+{
+    Ok(format!("{:032x}", murmur3_x64_128(reader, 0)?))
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    #[test]
+    fn test_hash_reader() {
+        let hello = hash_reader(&mut "hello".as_bytes()).unwrap();
+        assert_eq!(hello, hash_reader(&mut "hello".as_bytes()).unwrap());
+        assert_ne!(hello, hash_reader(&mut "hello\n".as_bytes()).unwrap());
+        assert_eq!(hello.len(), 32);
+    }
+
+    #[test]
+    fn test_hash_file_not_exist() {
+        assert!(hash_file(&crate::fs::RealFs, &"does/not/exist")
+            .unwrap()
+            .is_none());
+    }
+}
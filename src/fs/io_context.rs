@@ -1,60 +1,69 @@
-use crate::error::{PpError, PpErrorKind};
-use crate::fs::{normalize_path, AbsPath, GetLineEnding, TxtppPath};
+use crate::core::LineMarker;
+use crate::error::{PpError, PpErrorKind, VerifyDiff};
+use crate::fs::{get_line_ending_from_buf, hash_file, normalize_path, AbsPath, FileSystem};
 use crate::Mode;
 use error_stack::{Report, Result, ResultExt};
-use std::fs;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Lines, Read, Write};
+use similar::TextDiff;
+use std::io::{self, BufRead, Cursor, Lines, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Context for processing a txtpp file.
 ///
 /// This is an IO wrapper for reading from txtpp file and writing to the output file.
-#[derive(Debug)]
 pub struct IOCtx {
     /// Input reader
-    input: Lines<BufReader<File>>,
+    input: Lines<Box<dyn BufRead>>,
     /// Output wrapper
     out: CtxOut,
     pub cur_line: usize,
     pub work_dir: AbsPath,
     pub line_ending: &'static str,
     pub input_path: String,
+    /// The file system
+    fs: Arc<dyn FileSystem>,
+    /// Hash of the output written by the last run. The output is not overwritten if it was changed since then
+    last_output: Option<String>,
 }
 
 impl IOCtx {
-    /// Create a new IO context for the input txtpp file.
-    pub fn new(input_file: &AbsPath, mode: Mode) -> Result<Self, PpError> {
+    /// Create a new IO context for the input txtpp file and its output file.
+    ///
+    /// If `verify_diff` is true, the diff is attached to the error when verifying fails. See [`VerifyDiff`]
+    /// If `line_marker` is set, lines that are markers are ignored when verifying.
+    pub fn new(
+        input_file: &AbsPath,
+        output_path: PathBuf,
+        mode: Mode,
+        verify_diff: bool,
+        line_marker: Option<&LineMarker>,
+    ) -> Result<Self, PpError> {
         let input_path = input_file.to_string();
+        let fs = input_file.fs_arc();
 
-        let line_ending = input_file.get_line_ending().map_err(|e| {
-            e.change_context(Self::make_error_with_kind(
-                input_path.clone(),
-                PpErrorKind::OpenFile,
-            ))
-            .attach_printable(format!(
-                "could not read line ending for input file: `{input_path}`"
-            ))
-        })?;
-
-        let r = File::open(input_file)
-            .map(BufReader::new)
+        let r = fs
+            .open(input_file.as_path())
             .change_context_lazy(|| {
                 Self::make_error_with_kind(input_path.clone(), PpErrorKind::OpenFile)
             })
             .attach_printable_lazy(|| format!("could not open input file: `{input_path}`"))?;
-
-        let output_path = input_file.as_path_buf().remove_txtpp().map_err(|e| {
-            e.change_context(Self::make_error_with_kind(
-                input_path.clone(),
-                PpErrorKind::OpenFile,
-            ))
-            .attach_printable(format!(
-                "could not resolve output path for input file: `{input_path}`"
+        let (r, line_ending) = read_line_ending(r, &input_path).map_err(|e| {
+            e.attach_printable(format!(
+                "could not read line ending for input file: `{input_path}`"
             ))
         })?;
 
-        let out = CtxOut::new(mode, &input_path, &output_path)?;
+        let out = if (verify_diff || line_marker.is_some()) && mode == Mode::Verify {
+            CtxOut::VerifyInMemory {
+                name: input_file.display_with_base(&output_path),
+                path: output_path,
+                out: String::new(),
+                diff: verify_diff,
+                line_marker: line_marker.cloned(),
+            }
+        } else {
+            CtxOut::new(fs.as_ref(), mode, &input_path, &output_path)?
+        };
 
         let work_dir = input_file.parent().map_err(|e| {
             e.change_context(Self::make_error_with_kind(
@@ -74,9 +83,41 @@ impl IOCtx {
             line_ending,
             input_path,
             cur_line: 0,
+            fs,
+            last_output: None,
         })
     }
 
+    /// Create a new IO context that reads the txtpp source from a stream and writes the output to another stream.
+    ///
+    /// `name` is used in errors. Paths are resolved relative to `work_dir`, where commands are also run.
+    pub fn from_stream(
+        input: Box<dyn BufRead>,
+        name: &str,
+        work_dir: AbsPath,
+        output: Box<dyn Write>,
+    ) -> Result<Self, PpError> {
+        let (input, line_ending) = read_line_ending(input, name)?;
+        Ok(Self {
+            input: input.lines(),
+            out: CtxOut::Stream { out: output },
+            fs: work_dir.fs_arc(),
+            work_dir,
+            line_ending,
+            input_path: name.to_string(),
+            cur_line: 0,
+            last_output: None,
+        })
+    }
+
+    /// Set the hash of the output written by the last run.
+    ///
+    /// When building, an error is returned instead of overwriting an output that was changed since then,
+    /// unless the fresh output is the same as the changed one.
+    pub fn set_last_output(&mut self, hash: Option<String>) {
+        self.last_output = hash;
+    }
+
     /// Get the next line from the input file.
     pub fn next_line(&mut self) -> Option<Result<String, PpError>> {
         let line = self.input.next().map(|line| {
@@ -95,15 +136,19 @@ impl IOCtx {
     /// output directly as is.
     pub fn write_output(&mut self, output: &str) -> Result<(), PpError> {
         match &mut self.out {
-            CtxOut::Build { path, out } => out
+            CtxOut::Build { path, out, .. } => out
                 .write_all(output.as_bytes())
                 .change_context_lazy(|| make_error!(self, PpErrorKind::WriteFile))
                 .attach_printable_lazy(|| format!("cannot write to `{}`", path.display())),
-            CtxOut::InMemoryBuild { out, .. } => {
+            CtxOut::InMemoryBuild { out, .. } | CtxOut::VerifyInMemory { out, .. } => {
                 out.push_str(output);
                 Ok(())
             }
-            CtxOut::Clean { .. } => Ok(()), // do nothing
+            CtxOut::Stream { out } => out
+                .write_all(output.as_bytes())
+                .change_context_lazy(|| make_error!(self, PpErrorKind::WriteFile))
+                .attach_printable("cannot write to output stream"),
+            CtxOut::Clean | CtxOut::Plan => Ok(()), // do nothing
             CtxOut::Verify { path, out, rem } => {
                 log::debug!("verifying content: {output:?}");
                 // len is the length in bytes
@@ -131,9 +176,10 @@ impl IOCtx {
     pub fn write_temp_file(&mut self, temp_path: &str, contents: &str) -> Result<(), PpError> {
         let p = PathBuf::from(temp_path);
 
-        if let CtxOut::Clean { .. } = self.out {
+        if let CtxOut::Clean = self.out {
             if let Ok(export_file) = self.work_dir.try_resolve(&p, false) {
-                fs::remove_file(&export_file)
+                self.fs
+                    .remove_file(export_file.as_path())
                     .change_context_lazy(|| make_error!(self, PpErrorKind::DeleteFile))
                     .attach_printable_lazy(|| {
                         format!("could not remove temp file: `{export_file}`")
@@ -147,13 +193,15 @@ impl IOCtx {
             e.change_context(make_error!(self, PpErrorKind::WriteFile))
                 .attach_printable(format!("could not resolve temp file: `{}`", p.display()))
         })?;
-        if export_file.as_path().is_dir() {
+        if self.fs.is_dir(export_file.as_path()) {
             return Err(Report::new(make_error!(self, PpErrorKind::WriteFile))
                 .attach_printable(format!("cannot write to directory: `{export_file}`")));
         }
         // Check if the temp file already exists and has the same content
-        if export_file.as_path().exists() {
-            let current_content = fs::read_to_string(&export_file)
+        if self.fs.exists(export_file.as_path()) {
+            let current_content = self
+                .fs
+                .read_to_string(export_file.as_path())
                 .change_context_lazy(|| make_error!(self, PpErrorKind::ReadFile))
                 .attach_printable_lazy(|| {
                     format!("could not read existing temp file: `{export_file}`")
@@ -164,44 +212,168 @@ impl IOCtx {
             }
         }
 
-        fs::write(&export_file, contents)
+        write_atomic(self.fs.as_ref(), export_file.as_path(), contents.as_bytes())
             .change_context_lazy(|| make_error!(self, PpErrorKind::WriteFile))
             .attach_printable_lazy(|| format!("could not write temp file: `{export_file}`"))
     }
 
     /// Finish
-    pub fn done(mut self) -> Result<(), PpError> {
+    ///
+    /// Returns `true` if the output was written, and `false` if the output file was not touched,
+    /// either because of the mode or because it already has the fresh content.
+    pub fn done(mut self) -> Result<bool, PpError> {
         match &mut self.out {
-            CtxOut::Build { path, out } => out
+            CtxOut::Stream { out } => out
                 .flush()
                 .change_context_lazy(|| make_error!(self, PpErrorKind::WriteFile))
-                .attach_printable_lazy(|| format!("could not write to `{}`", path.display())),
+                .attach_printable("could not write to output stream")
+                .map(|_| true),
+            CtxOut::Build { .. } => {
+                // take the output out, so it's not removed when dropped
+                let (path, tmp_path, mut out) = match std::mem::replace(&mut self.out, CtxOut::Plan)
+                {
+                    CtxOut::Build {
+                        path,
+                        tmp_path,
+                        out,
+                    } => (path, tmp_path, out),
+                    _ => unreachable!(),
+                };
+                let result = out
+                    .flush()
+                    .map(|_| {
+                        // the writer is closed before checking and renaming
+                        drop(out);
+                    })
+                    .change_context_lazy(|| make_error!(self, PpErrorKind::WriteFile))
+                    .attach_printable_lazy(|| format!("could not write to `{}`", path.display()))
+                    .and_then(|_| self.check_not_edited(&path, Some(&tmp_path)))
+                    .and_then(|_| {
+                        self.fs
+                            .rename(&tmp_path, &path)
+                            .change_context_lazy(|| make_error!(self, PpErrorKind::WriteFile))
+                            .attach_printable_lazy(|| {
+                                format!("could not write to `{}`", path.display())
+                            })
+                    });
+                if result.is_err() {
+                    let _ = self.fs.remove_file(&tmp_path);
+                }
+                result.map(|_| true)
+            }
             CtxOut::InMemoryBuild { path, out } => {
-                if path.as_path().exists() {
-                    let current_content = fs::read_to_string(path.as_path())
+                let (path, out) = (&path.clone(), &std::mem::take(out));
+                if self.fs.exists(path) {
+                    let current_content = self
+                        .fs
+                        .read_to_string(path)
                         .change_context_lazy(|| make_error!(self, PpErrorKind::ReadFile))
                         .attach_printable_lazy(|| {
                             format!("could not read existing output file: `{}`", path.display())
                         })?; // early return because if we can't read it, we probably can't write it either
                     if &current_content == out {
                         log::debug!("output file already exists with same content, skipping");
-                        return Ok(());
+                        return Ok(false);
                     }
                 }
-                fs::write(path.as_path(), out)
+                self.check_not_edited(path, None)?;
+                create_parent_dir(self.fs.as_ref(), &self.input_path, path)?;
+                write_atomic(self.fs.as_ref(), path, out.as_bytes())
                     .change_context_lazy(|| make_error!(self, PpErrorKind::WriteFile))
                     .attach_printable_lazy(|| {
                         format!("could not write output file: `{}`", path.display())
                     })
+                    .map(|_| true)
             }
-            CtxOut::Clean { .. } => Ok(()), // do nothing
+            CtxOut::Clean | CtxOut::Plan => Ok(false), // do nothing
             CtxOut::Verify { path, rem, .. } => {
                 if *rem != 0 {
                     return Err(make_verify_report!(self, path));
                 }
-                Ok(())
+                Ok(false)
+            }
+            CtxOut::VerifyInMemory {
+                path,
+                name,
+                out,
+                diff,
+                line_marker,
+            } => {
+                // a missing file is treated as empty, so the diff shows the whole fresh output
+                let current_content = if self.fs.exists(path) {
+                    Some(
+                        self.fs
+                            .read_to_string(path)
+                            .change_context_lazy(|| make_error!(self, PpErrorKind::ReadFile))
+                            .attach_printable_lazy(|| {
+                                format!("could not read existing output file: `{}`", path.display())
+                            })?,
+                    )
+                } else {
+                    None
+                };
+                let same = match (&current_content, line_marker) {
+                    (Some(current), Some(marker)) => marker.strip(current) == marker.strip(out),
+                    (current, _) => current.as_ref() == Some(out),
+                };
+                if same {
+                    return Ok(false);
+                }
+                if !*diff {
+                    return Err(make_verify_report!(self, path));
+                }
+                let old_header = if current_content.is_some() {
+                    format!("a/{name}")
+                } else {
+                    "/dev/null".to_string()
+                };
+                let diff =
+                    TextDiff::from_lines(current_content.as_deref().unwrap_or_default(), out)
+                        .unified_diff()
+                        .header(&old_header, &format!("b/{name}"))
+                        .to_string();
+                Err(make_verify_report!(self, path).attach(VerifyDiff {
+                    file: name.clone(),
+                    diff,
+                }))
+            }
+        }
+    }
+
+    /// Check that the output file was not changed since the last run, before overwriting it.
+    ///
+    /// `fresh` is the file with the fresh output, if the fresh output could be the same as the
+    /// changed output. Nothing is lost by overwriting the output in that case.
+    fn check_not_edited(&self, path: &Path, fresh: Option<&Path>) -> Result<(), PpError> {
+        let last_output = match &self.last_output {
+            Some(hash) => hash,
+            None => return Ok(()),
+        };
+        let hash = |p: &Path| {
+            hash_file(self.fs.as_ref(), &p)
+                .change_context_lazy(|| make_error!(self, PpErrorKind::ReadFile))
+                .attach_printable_lazy(|| {
+                    format!(
+                        "could not read output file: `{}`",
+                        normalize_path(&p.display().to_string())
+                    )
+                })
+        };
+        let current = match hash(path)? {
+            Some(current) if &current != last_output => current,
+            _ => return Ok(()),
+        };
+        if let Some(fresh) = fresh {
+            if hash(fresh)?.as_ref() == Some(&current) {
+                return Ok(());
             }
         }
+        Err(
+            Report::new(make_error!(self, PpErrorKind::EditedOutput)).attach_printable(format!(
+                "`{}` was changed since it was generated. Use --force to overwrite it",
+                normalize_path(&path.display().to_string())
+            )),
+        )
     }
 
     pub fn make_error(&self, kind: PpErrorKind) -> PpError {
@@ -240,16 +412,19 @@ macro_rules! make_verify_report {
 use make_verify_report;
 
 /// Output context, which depends on the mode.
-#[derive(Debug)]
 enum CtxOut {
     /// Build mode.
     ///
-    /// Write to the output file.
+    /// Write to a temporary file next to the output file, which replaces the output file when done.
+    /// The temporary file is removed if the context is dropped before that, so the output is never
+    /// left half-written.
     Build {
         /// Path to the output file
         path: PathBuf,
+        /// Path to the temporary file being written
+        tmp_path: PathBuf,
         /// Output writer
-        out: BufWriter<File>,
+        out: Box<dyn Write>,
     },
     /// Build mode in memory
     ///
@@ -269,19 +444,52 @@ enum CtxOut {
     /// Read existing file and verify that it is the same as the fresh output
     Verify {
         path: PathBuf,
-        out: BufReader<File>,
+        out: Box<dyn BufRead>,
         rem: u64,
     },
+    /// Verify mode with diff or line markers.
+    ///
+    /// Keep the fresh output in memory and compare it with the existing file at the end,
+    /// so the difference can be reported, and line markers can be ignored
+    VerifyInMemory {
+        /// Path to the output file
+        path: PathBuf,
+        /// Path to the output file relative to the base directory, used in the diff header
+        name: String,
+        /// Output buffer
+        out: String,
+        /// If the diff is attached to the error
+        diff: bool,
+        /// Format of the line markers to ignore
+        line_marker: Option<LineMarker>,
+    },
+    /// Plan mode.
+    ///
+    /// Do nothing when writing
+    Plan,
+    /// Write to a stream instead of the output file, regardless of the mode
+    Stream {
+        /// Output writer
+        out: Box<dyn Write>,
+    },
 }
 
 impl CtxOut {
-    fn new<P>(mode: Mode, input_path: &str, output_path: &P) -> Result<Self, PpError>
+    fn new<P>(
+        fs: &dyn FileSystem,
+        mode: Mode,
+        input_path: &str,
+        output_path: &P,
+    ) -> Result<Self, PpError>
     where
         P: AsRef<Path>,
     {
         match mode {
             Mode::Build => {
-                let out = File::create(output_path)
+                create_parent_dir(fs, input_path, output_path.as_ref())?;
+                let tmp_path = tmp_path_of(output_path.as_ref());
+                let out = fs
+                    .create(&tmp_path)
                     .change_context_lazy(|| {
                         IOCtx::make_error_with_kind(input_path.to_string(), PpErrorKind::OpenFile)
                     })
@@ -290,11 +498,11 @@ impl CtxOut {
                             "could not create output file: `{}`",
                             normalize_path(&output_path.as_ref().display().to_string())
                         )
-                    })
-                    .map(BufWriter::new)?;
+                    })?;
                 Ok(Self::Build {
                     out,
                     path: output_path.as_ref().to_path_buf(),
+                    tmp_path,
                 })
             }
             Mode::InMemoryBuild => Ok(Self::InMemoryBuild {
@@ -303,8 +511,8 @@ impl CtxOut {
             }),
             Mode::Clean => {
                 let p = output_path.as_ref();
-                if p.exists() {
-                    fs::remove_file(p)
+                if fs.exists(p) {
+                    fs.remove_file(p)
                         .change_context_lazy(|| {
                             IOCtx::make_error_with_kind(
                                 input_path.to_string(),
@@ -320,9 +528,10 @@ impl CtxOut {
                 }
                 Ok(Self::Clean)
             }
+            Mode::Plan => Ok(Self::Plan),
             Mode::Verify => {
                 let p = output_path.as_ref();
-                if !p.exists() {
+                if !fs.exists(p) {
                     return Err(Report::new(IOCtx::make_error_with_kind(
                         input_path.to_string(),
                         PpErrorKind::VerifyOutput,
@@ -332,7 +541,8 @@ impl CtxOut {
                         normalize_path(&p.display().to_string())
                     )));
                 }
-                let len = fs::metadata(p)
+                let len = fs
+                    .file_len(p)
                     .change_context_lazy(|| {
                         IOCtx::make_error_with_kind(input_path.to_string(), PpErrorKind::OpenFile)
                     })
@@ -341,10 +551,10 @@ impl CtxOut {
                             "could not get metadata for output file: `{}`",
                             normalize_path(&p.display().to_string())
                         )
-                    })?
-                    .len();
+                    })?;
                 log::debug!("found output to verify, file size: {}", len);
-                let out = File::open(output_path)
+                let out = fs
+                    .open(p)
                     .change_context_lazy(|| {
                         IOCtx::make_error_with_kind(input_path.to_string(), PpErrorKind::OpenFile)
                     })
@@ -353,8 +563,7 @@ impl CtxOut {
                             "could not open output file: `{}`",
                             normalize_path(&p.display().to_string())
                         )
-                    })
-                    .map(BufReader::new)?;
+                    })?;
                 Ok(Self::Verify {
                     out,
                     rem: len,
@@ -364,3 +573,77 @@ impl CtxOut {
         }
     }
 }
+
+impl Drop for IOCtx {
+    fn drop(&mut self) {
+        // not done, so the partial output is discarded and the output file is untouched
+        if let CtxOut::Build { tmp_path, out, .. } = std::mem::replace(&mut self.out, CtxOut::Plan)
+        {
+            drop(out);
+            log::debug!("removing unfinished output: {}", tmp_path.display());
+            let _ = self.fs.remove_file(&tmp_path);
+        }
+    }
+}
+
+/// Get the path of the temporary file to write before replacing the file at `path`.
+///
+/// It's a hidden file in the same directory, so it can be renamed to `path` atomically
+fn tmp_path_of(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.{}.txtpp-tmp", std::process::id()))
+}
+
+/// Write the file by writing a temporary file and renaming it, so the file is either
+/// the old content or the new content, never partially written
+pub(crate) fn write_atomic(fs: &dyn FileSystem, path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = tmp_path_of(path);
+    let result = fs
+        .write(&tmp_path, contents)
+        .and_then(|_| fs.rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs.remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Create the parent directory of the output file if it doesn't exist, which is needed when using an output directory
+fn create_parent_dir(
+    fs: &dyn FileSystem,
+    input_path: &str,
+    output_path: &Path,
+) -> Result<(), PpError> {
+    match output_path.parent() {
+        Some(parent) if !fs.exists(parent) => fs
+            .create_dir_all(parent)
+            .change_context_lazy(|| {
+                IOCtx::make_error_with_kind(input_path.to_string(), PpErrorKind::OpenFile)
+            })
+            .attach_printable_lazy(|| {
+                format!(
+                    "could not create output directory: `{}`",
+                    normalize_path(&parent.display().to_string())
+                )
+            }),
+        _ => Ok(()),
+    }
+}
+
+/// Read the first line to get the line ending, then put it back
+fn read_line_ending(
+    mut input: Box<dyn BufRead>,
+    name: &str,
+) -> Result<(Box<dyn BufRead>, &'static str), PpError> {
+    let mut first_line = vec![];
+    let len = input
+        .read_until(b'\n', &mut first_line)
+        .change_context_lazy(|| {
+            IOCtx::make_error_with_kind(name.to_string(), PpErrorKind::ReadFile)
+        })
+        .attach_printable_lazy(|| format!("could not read input: `{name}`"))?;
+    let line_ending = get_line_ending_from_buf(&first_line, len);
+    Ok((Box::new(Cursor::new(first_line).chain(input)), line_ending))
+}
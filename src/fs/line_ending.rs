@@ -1,12 +1,5 @@
 //! Wrapper to perform file system operations
 
-use crate::error::PathError;
-use crate::fs::normalize_path;
-use error_stack::{Result, ResultExt};
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::Path;
-
 pub const CRLF: &str = "\r\n";
 pub const LF: &str = "\n";
 #[cfg(windows)]
@@ -14,31 +7,8 @@ pub const OS_LINE_ENDING: &str = CRLF;
 #[cfg(not(windows))]
 pub const OS_LINE_ENDING: &str = LF;
 
-pub trait GetLineEnding {
-    fn get_line_ending(&self) -> Result<&'static str, PathError>;
-}
-
-impl<P> GetLineEnding for P
-where
-    P: AsRef<Path>,
-{
-    fn get_line_ending(&self) -> Result<&'static str, PathError> {
-        let mut buf = vec![];
-        let len = File::open(self)
-            .map(BufReader::new)
-            .and_then(|mut r| r.read_until(b'\n', &mut buf))
-            .change_context_lazy(|| PathError::from(self))
-            .attach_printable_lazy(|| {
-                format!(
-                    "Failed to get line ending for file: {}",
-                    normalize_path(&self.as_ref().display().to_string())
-                )
-            })?;
-        Ok(get_line_ending_from_buf(&buf, len))
-    }
-}
-
-fn get_line_ending_from_buf(buf: &[u8], len: usize) -> &'static str {
+/// Get the line ending from the first line in the buffer, where `len` is the length of the line
+pub fn get_line_ending_from_buf(buf: &[u8], len: usize) -> &'static str {
     match len {
         0 => OS_LINE_ENDING,
         1 => {
@@ -1,13 +1,22 @@
 //! Wrapper to perform file system operations
 //!
+//! The [`FileSystem`] trait is the only public part. Set [`Config::fs`](crate::Config::fs)
+//! to run txtpp over a file system other than the real one, such as [`MemoryFs`].
 
 mod line_ending;
-pub use line_ending::GetLineEnding;
+pub(crate) use line_ending::get_line_ending_from_buf;
 mod path;
-pub use path::*;
+pub(crate) use path::*;
 
 mod shell;
-pub use shell::{Shell, TXTPP_FILE};
+pub use shell::TXTPP_FILE;
+pub(crate) use shell::{Shell, ShellError};
 
 mod io_context;
-pub use io_context::*;
+pub(crate) use io_context::*;
+
+mod hash;
+pub(crate) use hash::hash_file;
+
+mod file_system;
+pub use file_system::*;
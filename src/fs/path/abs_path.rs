@@ -1,9 +1,10 @@
 use crate::error::PathError;
-use crate::fs::normalize_path;
+use crate::fs::{normalize_path, FileSystem, RealFs};
 use derivative::Derivative;
 use error_stack::{Report, Result, ResultExt};
-use std::fs;
+use std::borrow::Borrow;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use super::TxtppPath;
 
@@ -17,14 +18,19 @@ pub const TXTPP_EXT: &str = "txtpp";
 ///
 /// We still use [`PathBuf`] in places that usually represent input from the user,
 /// as it could be relative or absolute and may not exist.
-#[derive(Debug, Clone, Derivative)]
-#[derivative(PartialEq, Eq, Hash)]
+///
+/// The path also carries the [`FileSystem`] it's in, which is shared by paths resolved from it.
+#[derive(Clone, Derivative)]
+#[derivative(Debug, PartialEq, Eq, Hash)]
 pub struct AbsPath {
     /// Base
     #[derivative(PartialEq = "ignore", Hash = "ignore")]
     b: PathBuf,
     /// Absolute path
     p: PathBuf,
+    /// The file system
+    #[derivative(Debug = "ignore", PartialEq = "ignore", Hash = "ignore")]
+    fs: Arc<dyn FileSystem>,
 }
 
 /// Integration with [`PathBuf`] and [`Path`]
@@ -41,6 +47,16 @@ impl AbsPath {
     pub fn as_path(&self) -> &Path {
         self.p.as_path()
     }
+    /// Get the file system the path is in
+    #[inline]
+    pub fn fs(&self) -> &dyn FileSystem {
+        self.fs.as_ref()
+    }
+    /// Get a shared handle to the file system the path is in
+    #[inline]
+    pub fn fs_arc(&self) -> Arc<dyn FileSystem> {
+        Arc::clone(&self.fs)
+    }
 }
 
 impl From<AbsPath> for PathBuf {
@@ -64,10 +80,22 @@ impl AsRef<Path> for AbsPath {
     }
 }
 
+/// Only the absolute path is used for equality and hashing, so it's safe to look up by [`Path`]
+impl Borrow<Path> for AbsPath {
+    #[inline]
+    fn borrow(&self) -> &Path {
+        self.as_path()
+    }
+}
+
 impl AbsPath {
-    /// Directly contruct from a [`PathBuf`]. Used ONLY in unit tests
+    /// Directly contruct from a [`PathBuf`] in the real file system. Used ONLY in unit tests
     pub fn new(p: PathBuf) -> Self {
-        Self { b: p.clone(), p }
+        Self {
+            b: p.clone(),
+            p,
+            fs: Arc::new(RealFs),
+        }
     }
 
     /// Convert a [`PathBuf`] to an absolute path as a base
@@ -77,12 +105,13 @@ impl AbsPath {
     /// - the path cannot be made absolute for some reason
     ///
     /// If the path is relative, it will be made absolute by
-    /// using [`canonicalize`](std::path::Path::canonicalize)
-    pub fn create_base(p: PathBuf) -> Result<Self, PathError> {
-        let p_abs = Self::make_abs(p)?;
+    /// using [`FileSystem::canonicalize`]
+    pub fn create_base(p: PathBuf, fs: Arc<dyn FileSystem>) -> Result<Self, PathError> {
+        let p_abs = Self::make_abs(fs.as_ref(), p)?;
         Ok(Self {
             b: p_abs.clone(),
             p: p_abs,
+            fs,
         })
     }
 
@@ -93,19 +122,20 @@ impl AbsPath {
     /// - the path cannot be made absolute for some reason
     ///
     /// If the path is relative, it will be made absolute by
-    /// using [`canonicalize`](std::path::Path::canonicalize)
+    /// using [`FileSystem::canonicalize`]
     pub fn share_base(&self, p: PathBuf) -> Result<Self, PathError> {
         Ok(Self {
             b: self.b.clone(),
-            p: Self::make_abs(p)?,
+            p: Self::make_abs(self.fs(), p)?,
+            fs: self.fs_arc(),
         })
     }
 
-    fn make_abs(p: PathBuf) -> Result<PathBuf, PathError> {
-        if !p.exists() {
+    fn make_abs(fs: &dyn FileSystem, p: PathBuf) -> Result<PathBuf, PathError> {
+        if !fs.exists(&p) {
             return Err(Report::new(PathError::from(&p)).attach_printable("path does not exist"));
         }
-        p.canonicalize()
+        fs.canonicalize(&p)
             .change_context_lazy(|| PathError::from(&p))
             .attach_printable("cannot convert path to absolute")
     }
@@ -125,8 +155,8 @@ impl AbsPath {
         } else {
             self.p.join(path)
         };
-        if !path_abs.exists() && create {
-            create_file(&path_abs)?;
+        if !self.fs.exists(&path_abs) && create {
+            create_file(self.fs(), &path_abs)?;
         }
         self.share_base(path_abs)
     }
@@ -148,6 +178,11 @@ impl AbsPath {
         let p = self.p.remove_txtpp()?;
         Ok(path_string_from_base(&self.b, &p))
     }
+
+    /// Display another path relative to the base of this path, like how this path is displayed
+    pub fn display_with_base(&self, p: &Path) -> String {
+        path_string_from_base(&self.b.as_path(), &p)
+    }
 }
 
 impl std::fmt::Display for AbsPath {
@@ -156,12 +191,12 @@ impl std::fmt::Display for AbsPath {
     }
 }
 
-fn create_file<P>(p: &P) -> Result<(), PathError>
+fn create_file<P>(fs: &dyn FileSystem, p: &P) -> Result<(), PathError>
 where
     P: AsRef<Path>,
 {
     log::debug!("creating file: {}", p.as_ref().display());
-    fs::File::create(p)
+    fs.write(p.as_ref(), &[])
         .change_context_lazy(|| PathError::from(p))
         .attach_printable("cannot create file")?;
     Ok(())
@@ -11,14 +11,17 @@
 //! the standard library types.
 
 use crate::error::PathError;
+use crate::fs::FileSystem;
 use error_stack::{Report, Result};
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
 mod abs_path;
 pub use abs_path::*;
 mod directory;
 pub use directory::*;
+mod out_dir;
+pub use out_dir::*;
 
 pub trait TxtppPath: Sized {
     /// Get the path to the txtpp file corresponding to the current path.
@@ -28,8 +31,8 @@ pub trait TxtppPath: Sized {
     /// - foo.txtpp.bar
     ///
     /// If the path is already a txtpp file, `None` will be returned.
-    /// Otherwise, if a corresponding txtpp file exists, that will be returned
-    fn get_txtpp_file(&self) -> Option<Self>;
+    /// Otherwise, if a corresponding txtpp file exists in `fs`, that will be returned
+    fn get_txtpp_file(&self, fs: &dyn FileSystem) -> Option<Self>;
 
     /// Check if the path matches a txtpp file format. The path can be non-existent and this will
     /// still return true as long as the format is correct.
@@ -61,7 +64,7 @@ impl TxtppPath for PathBuf {
         }
     }
 
-    fn get_txtpp_file(&self) -> Option<Self> {
+    fn get_txtpp_file(&self, fs: &dyn FileSystem) -> Option<Self> {
         if self.is_txtpp_file() {
             return None;
         }
@@ -75,7 +78,7 @@ impl TxtppPath for PathBuf {
                 ext1.push(TXTPP_EXT);
                 p.set_extension(ext1);
 
-                if p.is_file() {
+                if fs.is_file(&p) {
                     return Some(p);
                 }
                 p.set_extension(""); // restore p
@@ -83,7 +86,7 @@ impl TxtppPath for PathBuf {
                 ext2.push(".");
                 ext2.push(ext);
                 p.set_extension(ext2);
-                if p.is_file() {
+                if fs.is_file(&p) {
                     Some(p)
                 } else {
                     None
@@ -93,7 +96,7 @@ impl TxtppPath for PathBuf {
                 // if there's no extension, the only way is adding .txtpp at the end
                 let ext = OsString::from(TXTPP_EXT);
                 p.set_extension(ext);
-                if p.is_file() {
+                if fs.is_file(&p) {
                     Some(p)
                 } else {
                     None
@@ -123,6 +126,25 @@ impl TxtppPath for PathBuf {
     }
 }
 
+/// Resolve `.` and `..` in the path without accessing the file system
+///
+/// This is for paths that may not exist yet, so they can't be canonicalized.
+pub fn clean_path(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !out.pop() && !out.has_root() {
+                    out.push(component);
+                }
+            }
+            component => out.push(component),
+        }
+    }
+    out
+}
+
 #[cfg(windows)]
 #[inline]
 pub fn normalize_path(p: &str) -> &str {
@@ -143,6 +165,15 @@ pub fn normalize_path(p: &str) -> &str {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_clean_path() {
+        assert_eq!(
+            clean_path(Path::new("/a/./sub/../b.txt")),
+            PathBuf::from("/a/b.txt")
+        );
+        assert_eq!(clean_path(Path::new("../a/b/..")), PathBuf::from("../a"));
+    }
+
     #[test]
     fn test_is_txtpp_file() {
         assert!(PathBuf::from("foo.txtpp").is_txtpp_file());
@@ -0,0 +1,90 @@
+use super::TxtppPath;
+use crate::error::PathError;
+use crate::fs::FileSystem;
+use error_stack::{Report, Result};
+use std::path::{Path, PathBuf};
+
+/// Mapping between `.txtpp` files and their outputs
+///
+/// By default, the output is next to the `.txtpp` file. With an output directory,
+/// the source tree is mirrored under the output directory.
+#[derive(Debug, Clone, Default)]
+pub struct OutDir {
+    /// The absolute source root and output root, if outputs are in a separate directory
+    roots: Option<(PathBuf, PathBuf)>,
+}
+
+impl OutDir {
+    /// Outputs are next to the `.txtpp` files
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Outputs are under `out_root`, mirroring the tree under `src_root`. Both paths should be absolute.
+    pub fn with_root(src_root: PathBuf, out_root: PathBuf) -> Self {
+        Self {
+            roots: Some((src_root, out_root)),
+        }
+    }
+
+    /// Get the output path of a `.txtpp` file
+    ///
+    /// An error is returned if the path is not a `.txtpp` file, or if it's not in the source root
+    /// when using an output directory.
+    pub fn output_of(&self, txtpp_file: &Path) -> Result<PathBuf, PathError> {
+        let output = txtpp_file.to_path_buf().remove_txtpp()?;
+        let (src_root, out_root) = match &self.roots {
+            Some(roots) => roots,
+            None => return Ok(output),
+        };
+        match output.strip_prefix(src_root) {
+            Ok(rel) => Ok(out_root.join(rel)),
+            Err(_) => Err(Report::new(PathError::from(&txtpp_file))
+                .attach_printable(format!(
+                    "file is not under the source root `{}` and cannot be mapped to the output directory",
+                    src_root.display()
+                ))),
+        }
+    }
+
+    /// Get the `.txtpp` file that generates the path, if any
+    ///
+    /// The path can be the output path, or the path next to the `.txtpp` file as if there is
+    /// no output directory. If the path is already a `.txtpp` file, `None` is returned.
+    pub fn source_of(&self, path: &Path, fs: &dyn FileSystem) -> Option<PathBuf> {
+        if let Some((src_root, out_root)) = &self.roots {
+            if let Ok(rel) = path.strip_prefix(out_root) {
+                return src_root.join(rel).get_txtpp_file(fs);
+            }
+        }
+        path.to_path_buf().get_txtpp_file(fs)
+    }
+
+    /// Get the path to read for the path, which is the output path if the path is generated by a `.txtpp` file
+    pub fn resolve_generated(&self, path: &Path, fs: &dyn FileSystem) -> PathBuf {
+        self.source_of(path, fs)
+            .and_then(|source| self.output_of(&source).ok())
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+}
+
+#[cfg(test)]
+mod ut {
+    use super::*;
+
+    #[test]
+    fn test_output_of() {
+        let out_dir = OutDir::new();
+        assert_eq!(
+            out_dir.output_of(Path::new("/a/b.txtpp")).unwrap(),
+            PathBuf::from("/a/b")
+        );
+        let out_dir = OutDir::with_root("/src".into(), "/src/build/gen".into());
+        assert_eq!(
+            out_dir.output_of(Path::new("/src/a/b.txt.txtpp")).unwrap(),
+            PathBuf::from("/src/build/gen/a/b.txt")
+        );
+        assert!(out_dir.output_of(Path::new("/other/b.txtpp")).is_err());
+        assert!(out_dir.output_of(Path::new("/src/b.txt")).is_err());
+    }
+}
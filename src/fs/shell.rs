@@ -1,11 +1,13 @@
 //! Utilities for running shell commands
 
 use super::path::AbsPath;
+use super::RealFs;
 use error_stack::{Report, Result, ResultExt};
 use std::error;
 use std::fmt::{Display, Formatter};
 use std::path::Path;
 use std::process::Command;
+use std::sync::Arc;
 use which::which;
 
 /// Name of the environment variable set for subcommands for the current file being processed
@@ -31,12 +33,14 @@ impl error::Error for ShellError {}
 
 /// Representation of a resolved shell command like `sh -c` or `cmd /C`
 /// that takes a command as argument.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Shell {
     /// The shell executable
     exe: String,
     /// The arguments of the shell executable (for example, `-c`)
     args: Vec<String>,
+    /// Environment variables set for the commands
+    env: Vec<(String, String)>,
 }
 
 impl Display for Shell {
@@ -68,16 +72,31 @@ impl Shell {
         let args = args.map(String::from).collect::<Vec<_>>();
 
         // Resolve the absolute path of the shell executable
-        Ok(Self { exe, args })
+        Ok(Self {
+            exe,
+            args,
+            env: vec![],
+        })
+    }
+
+    /// Set environment variables for the commands
+    pub fn with_env<I>(mut self, env: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.env.extend(env);
+        self.env.sort();
+        self
     }
 
     /// Run the shell with the given argument in the directory. Return the stdout.
     pub fn run(&self, command: &str, work_dir: &AbsPath, file: &str) -> Result<String, ShellError> {
         log::debug!("shell command `{command}`");
         let result = Command::new(&self.exe)
-            .current_dir(work_dir.to_string())
+            .current_dir(work_dir.as_path())
             .args(&self.args)
             .arg(command)
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
             .env(TXTPP_FILE, file)
             .output()
             .change_context(ShellError::ExecuteError)
@@ -115,7 +134,7 @@ fn resolve_shell(exe: &str) -> Result<AbsPath, ShellError> {
 
     let path = p.display().to_string();
 
-    AbsPath::create_base(p).map_err(|e| {
+    AbsPath::create_base(p, Arc::new(RealFs)).map_err(|e| {
         e.change_context(ShellError::ResolveError)
             .attach_printable(format!(
                 "could not convert shell executable to absolute path: {}",
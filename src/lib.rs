@@ -12,8 +12,17 @@
 //!
 //! - [`Config`] object to configure txtpp. This is what the CLI uses under the hood.
 //! - [`txtpp`] and [`Txtpp::run`] to consume the [`Config`] and run txtpp.
-//! - [`Mode`] and [`Verbosity`] used in the config
+//!   [`Txtpp::run`] also returns a [`RunReport`] with what was done to each file.
+//! - [`Observer`] to receive [`Event`]s during a run, such as files finished and directives executed.
+//!   The default is [`TerminalObserver`], which prints the status to stderr.
+//!   [`JsonObserver`] writes the events as newline-delimited JSON instead.
+//! - [`watch`] and [`Watcher`] to rebuild files when they change.
+//! - [`expand`] to preprocess from a reader to a writer without output files.
+//! - [`process_str`] and [`ProcessOptions`] to preprocess a string in memory, for example in build scripts.
+//! - [`Mode`], [`Verbosity`] and [`GraphFormat`] used in the config
+//! - [`SourceMap`] to read the `.map` files written with [`Config::source_map`]
 //! - [`error`] module for explicit error handling
+//! - [`fs`] module to run txtpp over a file system other than the real one, such as [`fs::MemoryFs`]
 //!
 //! ## Examples
 //! ### Running out-of-box
@@ -40,7 +49,7 @@
 //! ```
 //!
 //! ### Custom config and error handling
-//! You can also create a [`Config`] from scratch
+//! You can also create a [`Config`] from scratch. Options not specified below are left as default
 //! ```no_run
 //! use txtpp::{Txtpp, Config, Mode, Verbosity};
 //!
@@ -53,6 +62,7 @@
 //!     mode: Mode::Verify,
 //!     verbosity: Verbosity::Quiet,
 //!     trailing_newline: false,
+//!     ..Default::default()
 //! };
 //!
 //! if let Err(e) = Txtpp::run(config) {
@@ -71,7 +81,12 @@
 //! ```
 //!
 mod core;
-pub use crate::core::{txtpp, Config, Mode, Txtpp, Verbosity};
+pub use crate::core::{
+    expand, process_str, txtpp, watch, Config, ConfigFile, Event, FileReport, FileStatus,
+    GraphFormat, JsonObserver, Mapping, Mode, Observer, Origin, ProcessOptions, RunReport,
+    SourceMap, SymlinkPolicy, TerminalObserver, Txtpp, Verbosity, Watcher, CACHE_FILE, CONFIG_FILE,
+    DEFAULT_BANNER, DEFAULT_LINE_MARKER, IGNORE_FILE,
+};
 pub mod error;
-mod fs;
+pub mod fs;
 pub use crate::fs::TXTPP_FILE;
@@ -1,7 +1,17 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use error_stack::Report;
 use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
-use txtpp::{txtpp, Config, Mode, Verbosity, TXTPP_FILE};
+use std::sync::Arc;
+use std::time::Duration;
+use txtpp::error::TxtppError;
+use txtpp::{
+    expand, watch, Config, GraphFormat, JsonObserver, Mode, SymlinkPolicy, Txtpp, Verbosity,
+    DEFAULT_BANNER, TXTPP_FILE,
+};
 
 /// txtpp CLI
 ///
@@ -26,9 +36,59 @@ struct Cli {
     /// Note that this will increase memory usage and may fail if the file cannot fit in memory.
     #[arg(short = 'N', long)]
     needed: bool,
+
+    /// Skip files whose inputs are unchanged since the last run
+    ///
+    /// The content hashes of each `.txtpp` file, its `include`/`after` dependencies,
+    /// temporary files and the output are stored in `.txtpp-cache` in the current directory.
+    /// A file is skipped if none of them changed since the last run.
+    #[arg(short, long)]
+    incremental: bool,
+
+    /// Process every file even if it's up-to-date in the build cache, and overwrite outputs changed by hand
+    ///
+    /// The cache is still updated.
+    #[arg(short, long)]
+    force: bool,
+
+    /// Write a Makefile-syntax `.d` depfile next to each output, for use in Make or Ninja
+    ///
+    /// The depfile lists the `.txtpp` file and every file from `include` and `after` directives,
+    /// like `gcc -MD`.
+    #[arg(long)]
+    depfile: bool,
+
+    /// Write a JSON `.map` source map next to each output
+    ///
+    /// The source map maps ranges of lines in the output to the `.txtpp` line, included file
+    /// or directive they are from. See https://docs.rs/txtpp/latest/txtpp/struct.SourceMap.html
+    #[arg(long)]
+    source_map: bool,
+
+    /// Files or directories to always process with `-i/--incremental`
+    ///
+    /// Use this for files with `run` directives that depend on things not tracked by txtpp.
+    #[arg(long)]
+    always_dirty: Vec<String>,
 }
 
 impl Cli {
+    /// Get the polling interval if running in watch mode
+    fn watch_interval(&self) -> Option<Duration> {
+        match &self.subcommand {
+            Some(Command::Watch { interval, .. }) => Some(Duration::from_millis(*interval)),
+            _ => None,
+        }
+    }
+
+    /// Get the input if running in expand mode
+    fn expand_input(&self) -> Option<&str> {
+        match &self.subcommand {
+            Some(Command::Expand { input, .. }) => Some(input),
+            _ => None,
+        }
+    }
+
     fn apply_to(&self, config: &mut Config) {
         match &self.subcommand {
             Some(subcommand) => subcommand.apply_to(config),
@@ -38,6 +98,11 @@ impl Cli {
                 } else {
                     Mode::Build
                 };
+                config.overrides.incremental = flag(self.incremental);
+                config.force = self.force;
+                config.always_dirty = self.always_dirty.clone();
+                config.overrides.depfile = flag(self.depfile);
+                config.overrides.source_map = flag(self.source_map);
                 self.flags.apply_to(config);
                 self.shell.apply_to(config);
             }
@@ -53,6 +118,24 @@ enum Command {
     Clean {
         #[command(flatten)]
         flags: Flags,
+        /// Also remove the depfiles written with `--depfile`
+        #[arg(long)]
+        depfile: bool,
+        /// Also remove the source maps written with `--source-map`
+        #[arg(long)]
+        source_map: bool,
+        /// Remove the files of `.txtpp` files that were deleted, instead of cleaning the inputs
+        ///
+        /// The files produced by each `.txtpp` file are recorded in `.txtpp-cache` when building.
+        /// Outputs that were changed since they were generated are only removed with `--force`.
+        #[arg(long)]
+        orphans: bool,
+        /// Print the files that `--orphans` would remove without removing them
+        #[arg(long, requires = "orphans")]
+        dry_run: bool,
+        /// Remove orphaned outputs even if they were changed since they were generated
+        #[arg(short, long, requires = "orphans")]
+        force: bool,
     },
     /// Verify that files generated by txtpp are up to date
     ///
@@ -62,18 +145,146 @@ enum Command {
         flags: Flags,
         #[command(flatten)]
         shell: BuildFlags,
+        /// Print a colored unified diff for outputs that are different from the fresh output
+        #[arg(long)]
+        diff: bool,
+        /// Also write the diffs to a patch file that can be applied to update the outputs
+        ///
+        /// Implies `--diff`. The patch can be applied with `git apply` or `patch -p1`.
+        #[arg(long, value_name = "FILE")]
+        patch: Option<PathBuf>,
+    },
+    /// Print what would be built without building
+    ///
+    /// See https://docs.rs/txtpp/latest/txtpp/enum.Mode.html#variant.Plan for more details
+    Plan {
+        #[command(flatten)]
+        flags: Flags,
+    },
+    /// Print the dependency graph of the `.txtpp` files without building
+    ///
+    /// The graph includes the output of each file, the `include`/`after` dependencies
+    /// and the temporary files. Like `plan`, no command is run and nothing is written.
+    Graph {
+        #[command(flatten)]
+        flags: Flags,
+        /// The format of the graph
+        #[arg(long, value_enum, default_value = "dot")]
+        format: Format,
+    },
+    /// Preprocess one source and print the output to stdout
+    ///
+    /// No output file is written. The outputs of other `.txtpp` files are included as they are,
+    /// without building them first.
+    Expand {
+        #[command(flatten)]
+        shell: BuildFlags,
+        /// The `.txtpp` file to preprocess, or `-` to read from stdin
+        #[arg(default_value = "-")]
+        input: String,
+        /// Directory to resolve paths and run commands in
+        ///
+        /// Defaults to the directory of the input file, or the current directory when reading from stdin.
+        #[arg(long)]
+        cwd: Option<PathBuf>,
+        /// Don't load settings from `txtpp.toml` files
+        ///
+        /// By default, the closest `txtpp.toml` in the `--cwd` directory or its ancestors is loaded.
+        #[arg(long)]
+        no_config: bool,
+    },
+    /// Build output files, then rebuild them when the inputs change
+    ///
+    /// The files are polled for changes. When a `.txtpp` file, an included file or an `after` target
+    /// changes, only the affected files and the files that depend on them are rebuilt.
+    Watch {
+        #[command(flatten)]
+        flags: Flags,
+        #[command(flatten)]
+        shell: BuildFlags,
+        /// Only touch output file if the content need to change
+        ///
+        /// See `--needed` of the default command
+        #[arg(short = 'N', long)]
+        needed: bool,
+        /// Interval in milliseconds to poll for changes
+        #[arg(long, default_value = "500")]
+        interval: u64,
     },
 }
 
 impl Command {
     fn apply_to(&self, config: &mut Config) {
         match self {
-            Command::Clean { flags } => {
+            Command::Clean {
+                flags,
+                depfile,
+                source_map,
+                orphans,
+                dry_run,
+                force,
+            } => {
                 config.mode = Mode::Clean;
+                config.overrides.depfile = flag(*depfile);
+                config.overrides.source_map = flag(*source_map);
+                config.orphans = *orphans;
+                config.dry_run = *dry_run;
+                config.force = *force;
                 flags.apply_to(config);
             }
-            Command::Verify { flags, shell } => {
+            Command::Verify {
+                flags,
+                shell,
+                diff,
+                patch,
+            } => {
                 config.mode = Mode::Verify;
+                config.verify_diff = *diff || patch.is_some();
+                config.patch_file = patch.clone();
+                flags.apply_to(config);
+                shell.apply_to(config);
+            }
+            Command::Plan { flags } => {
+                config.mode = Mode::Plan;
+                flags.apply_to(config);
+            }
+            Command::Graph { flags, format } => {
+                config.mode = Mode::Plan;
+                config.graph_format = Some(match format {
+                    Format::Dot => GraphFormat::Dot,
+                    Format::Json => GraphFormat::Json,
+                });
+                flags.apply_to(config);
+            }
+            Command::Expand {
+                shell,
+                input,
+                cwd,
+                no_config,
+            } => {
+                config.base_dir = match cwd {
+                    Some(cwd) => cwd.clone(),
+                    None if input == STDIN => PathBuf::from("."),
+                    None => Path::new(input)
+                        .parent()
+                        .filter(|p| !p.as_os_str().is_empty())
+                        .map(Path::to_path_buf)
+                        .unwrap_or_else(|| PathBuf::from(".")),
+                };
+                config.config_files = !*no_config;
+                shell.apply_to(config);
+            }
+            Command::Watch {
+                flags,
+                shell,
+                needed,
+                ..
+            } => {
+                config.mode = if *needed {
+                    Mode::InMemoryBuild
+                } else {
+                    Mode::Build
+                };
                 flags.apply_to(config);
                 shell.apply_to(config);
             }
@@ -81,6 +292,15 @@ impl Command {
     }
 }
 
+/// Format of the `graph` subcommand
+#[derive(Debug, Clone, ValueEnum)]
+enum Format {
+    /// The DOT language of Graphviz
+    Dot,
+    /// JSON
+    Json,
+}
+
 #[derive(Debug, Clone, Args)]
 struct Flags {
     /// Show no output.
@@ -100,9 +320,86 @@ struct Flags {
     #[arg(short, long)]
     recursive: bool,
 
-    /// Specify the number of worker threads
-    #[arg(short = 'j', long, default_value = "4")]
-    threads: usize,
+    /// Only process `.txtpp` files found in directories that match one of the patterns
+    ///
+    /// The patterns use `.gitignore` syntax and are relative to the current directory.
+    /// Files given directly as inputs are always processed.
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// Skip files and directories that match the pattern when scanning directories
+    ///
+    /// The patterns use `.gitignore` syntax and are relative to the current directory.
+    /// Excluded directories are not scanned. `.txtppignore` files are always honored the same way.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Also honor `.gitignore` files when scanning directories
+    #[arg(long)]
+    gitignore: bool,
+
+    /// How to treat symbolic links
+    ///
+    /// Links are resolved to their targets, so a `.txtpp` file reached through a link is processed
+    /// as the target file. With both `follow` and `follow-once`, a file or directory reached through
+    /// several links is processed once.
+    #[arg(long, value_enum, default_value = "follow-once")]
+    symlinks: Symlinks,
+
+    /// The maximum depth of subdirectories to scan with `-r/--recursive`
+    ///
+    /// The input directories are at depth 0.
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// Keep processing other files when a file fails
+    ///
+    /// Files that depend on a failed file are skipped. All failures are reported at the end.
+    #[arg(short, long)]
+    keep_going: bool,
+
+    /// Directory to write outputs to, mirroring the input tree
+    ///
+    /// Included outputs of other `.txtpp` files are read from this directory.
+    /// `clean` and `verify` also operate on this directory. Temporary files are still written
+    /// next to the `.txtpp` files.
+    #[arg(short, long)]
+    out_dir: Option<PathBuf>,
+
+    /// Specify the number of worker threads [default: 4]
+    #[arg(short = 'j', long)]
+    threads: Option<usize>,
+
+    /// Run the commands of `run` directives in a file concurrently
+    ///
+    /// Outputs are written in the same order as the directives. Other directives wait for the
+    /// commands before them to finish. Use `run-seq` for commands that should not run concurrently.
+    /// At most `--threads` commands run at the same time.
+    #[arg(long)]
+    parallel_run: bool,
+
+    /// Write line markers to the outputs with the extension, like `c` or `c=FORMAT`
+    ///
+    /// `{line}` and `{file}` in the format are replaced with the line number and the file the next line is from.
+    /// The default format is `#line {line} "{file}"`. Markers are ignored when verifying.
+    #[arg(long, value_name = "EXT[=FORMAT]")]
+    line_markers: Vec<String>,
+
+    /// Add a banner comment to the start of the outputs, after the shebang if any
+    ///
+    /// `{file}` in the text is replaced with the `.txtpp` file. The default text is
+    /// "DO NOT EDIT - generated by txtpp from {file}". The comment syntax is from the output extension,
+    /// and outputs with an unknown extension like `.txt` don't have the banner. Use `--banner=TEXT` to set the text.
+    #[arg(long, value_name = "TEXT", num_args = 0..=1, default_missing_value = DEFAULT_BANNER)]
+    banner: Option<String>,
+
+    /// The format of the status messages
+    ///
+    /// With `json`, each event is printed to stdout as one line of JSON instead of the
+    /// status lines on stderr. See https://docs.rs/txtpp/latest/txtpp/struct.JsonObserver.html
+    /// for the messages.
+    #[arg(long, value_enum, default_value = "human")]
+    message_format: MessageFormat,
 
     /// Input files and/or directories
     ///
@@ -111,8 +408,13 @@ struct Flags {
     /// Subdirectories will not be processed unless `-r/--recursive` is specified.
     ///
     /// The current directory is used if no input is specified.
-    #[arg(default_value = ".")]
     inputs: Vec<String>,
+    /// Don't load settings from `txtpp.toml` files
+    ///
+    /// By default, the closest `txtpp.toml` in the current directory or its ancestors is loaded,
+    /// and the ones in subdirectories apply to the files under them. Flags take precedence over the files.
+    #[arg(long)]
+    no_config: bool,
 }
 
 impl Flags {
@@ -122,12 +424,51 @@ impl Flags {
         } else if self.verbose {
             config.verbosity = Verbosity::Verbose;
         }
-        config.recursive = self.recursive;
-        config.num_threads = self.threads;
-        config.inputs = self.inputs.clone();
+        config.config_files = !self.no_config;
+        config.symlinks = match self.symlinks {
+            Symlinks::Follow => SymlinkPolicy::Follow,
+            Symlinks::FollowOnce => SymlinkPolicy::FollowOnce,
+            Symlinks::Skip => SymlinkPolicy::Skip,
+        };
+        let overrides = &mut config.overrides;
+        overrides.recursive = flag(self.recursive);
+        overrides.include = non_empty(&self.include);
+        overrides.exclude = non_empty(&self.exclude);
+        overrides.gitignore = flag(self.gitignore);
+        overrides.max_depth = self.max_depth;
+        overrides.keep_going = flag(self.keep_going);
+        overrides.out_dir = self.out_dir.clone();
+        overrides.threads = self.threads;
+        overrides.parallel_run = flag(self.parallel_run);
+        overrides.line_markers = self.line_markers.clone();
+        overrides.banner = self.banner.clone();
+        overrides.inputs = non_empty(&self.inputs);
+        if let MessageFormat::Json = self.message_format {
+            config.observer = Some(Arc::new(JsonObserver::stdout()));
+        }
     }
 }
 
+/// How to treat symbolic links
+#[derive(Debug, Clone, ValueEnum)]
+enum Symlinks {
+    /// Follow links, and fail if a directory link loops back
+    Follow,
+    /// Follow links, and skip directory links that loop back
+    FollowOnce,
+    /// Don't follow links. Links in the inputs or as `include`/`after` targets are errors
+    Skip,
+}
+
+/// Format of the status messages
+#[derive(Debug, Clone, ValueEnum)]
+enum MessageFormat {
+    /// Colored status lines on stderr
+    Human,
+    /// Newline-delimited JSON on stdout
+    Json,
+}
+
 #[derive(Debug, Clone, Args)]
 struct BuildFlags {
     /// The shell command to use
@@ -139,8 +480,8 @@ struct BuildFlags {
     /// If a shell is not specified, the platform-specific default shell will be used,
     /// which is `sh -c` on non-Windows. PowerShell is used on Windows with CMD as a fallback.
     /// See https://github.com/Pistonite/txtpp#run-directive for the default PowerShell flags used.
-    #[arg(short, long, default_value = "")]
-    shell: String,
+    #[arg(short, long)]
+    shell: Option<String>,
 
     /// Don't add a trailing newline to the output.
     ///
@@ -149,15 +490,75 @@ struct BuildFlags {
     /// for more details.
     #[arg(short, long)]
     no_trailing_newline: bool,
+
+    /// Set an environment variable for the commands, like `NAME=VALUE`
+    ///
+    /// Can be specified multiple times. These are added after the `[env]` table in `txtpp.toml`.
+    #[arg(short = 'D', long = "define", value_name = "NAME=VALUE")]
+    defines: Vec<String>,
 }
 
 impl BuildFlags {
     fn apply_to(&self, config: &mut Config) {
-        config.shell_cmd = self.shell.clone();
-        config.trailing_newline = !self.no_trailing_newline;
+        let overrides = &mut config.overrides;
+        overrides.shell = self.shell.clone();
+        overrides.trailing_newline = self.no_trailing_newline.then_some(false);
+        overrides.defines = self.defines.clone();
     }
 }
 
+/// Convert a flag to a setting that is only set if the flag is given
+fn flag(value: bool) -> Option<bool> {
+    value.then_some(true)
+}
+
+/// Convert a list of arguments to a setting that is only set if the list is not empty
+fn non_empty(values: &[String]) -> Option<Vec<String>> {
+    (!values.is_empty()).then(|| values.to_vec())
+}
+
+/// Run txtpp and print the plan or the dependency graph, or the orphaned files with `clean --dry-run`, to stdout
+///
+/// With `--message-format json`, they are in the `finished` message instead.
+fn run_txtpp(config: Config) -> Result<(), ()> {
+    let print = config.observer.is_none();
+    let list_orphans = config.orphans && config.dry_run;
+    let base = config
+        .base_dir
+        .canonicalize()
+        .unwrap_or_else(|_| config.base_dir.clone());
+    let report = Txtpp::run(config).map_err(|e| eprintln!("{:?}", e))?;
+    if !print {
+        return Ok(());
+    }
+    if let Some(plan) = &report.plan {
+        print!("{plan}");
+    }
+    if list_orphans {
+        for file in &report.orphan_files {
+            println!("{}", file.strip_prefix(&base).unwrap_or(file).display());
+        }
+    }
+    Ok(())
+}
+
+/// Input name for reading from stdin
+const STDIN: &str = "-";
+
+/// Expand the input file or stdin to stdout
+fn run_expand(input: &str, config: &Config) -> Result<(), Report<TxtppError>> {
+    let output = BufWriter::new(io::stdout().lock());
+    if input == STDIN {
+        return expand(io::stdin().lock(), "<stdin>", output, config);
+    }
+    let file = File::open(input).map(BufReader::new).map_err(|e| {
+        Report::new(e)
+            .change_context(TxtppError::default())
+            .attach_printable(format!("cannot open input file: `{input}`"))
+    })?;
+    expand(file, input, output, config)
+}
+
 fn main() -> ExitCode {
     if let Ok(f) = env::var(TXTPP_FILE) {
         if !f.is_empty() {
@@ -173,7 +574,16 @@ fn main() -> ExitCode {
     let mut config = Config::default();
     args.apply_to(&mut config);
 
-    match txtpp(config) {
+    let result = if let Some(input) = args.expand_input() {
+        run_expand(input, &config).map_err(|e| eprintln!("{:?}", e))
+    } else {
+        match args.watch_interval() {
+            Some(interval) => watch(config, interval).map_err(|e| eprintln!("{:?}", e)),
+            None => run_txtpp(config),
+        }
+    };
+
+    match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(_) => ExitCode::FAILURE,
     }
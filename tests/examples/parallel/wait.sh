@@ -0,0 +1,8 @@
+#!/bin/sh
+# Wait for the file to be created by another command, which fails if the commands don't run concurrently
+i=0
+while [ ! -f "$1" ] && [ $i -lt 200 ]; do
+    sleep 0.05
+    i=$((i + 1))
+done
+test -f "$1"
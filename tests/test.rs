@@ -67,7 +67,12 @@ testit!(tests__examples__circular_dep, |env| {
 
 testit!(tests__examples__circular_dep_self, |env| {
     assert!(env.run().is_err());
-    env.assert_file_eq("invalid", "invalid.expected");
+    // the output is not written when processing fails
+    env.assert_path_exists("invalid", false);
+    env.set_file("invalid", "previous output\n");
+    assert!(env.run().is_err());
+    let output = std::fs::read_to_string(env.cfg.base_dir.join("invalid")).unwrap();
+    assert_eq!(output, "previous output\n");
 });
 
 testit!(tests__examples__temp__write_clean, |env| {
@@ -238,3 +243,748 @@ testit!(tests__examples__after, |env| {
     assert!(env.run().is_ok());
     env.assert_file_eq("example", "example.expected");
 });
+
+testit!(tests__examples__incremental, |env| {
+    env.cfg.incremental = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("foo.txt", "foo.txt.expected");
+    env.assert_path_exists(CACHE_FILE, true);
+    let modified = env.get_modification_time("foo.txt").unwrap();
+    let modified_baz = env.get_modification_time("baz.txt").unwrap();
+
+    // nothing changed, should not rewrite
+    assert!(env.run().is_ok());
+    assert_eq!(modified, env.get_modification_time("foo.txt").unwrap());
+    assert_eq!(modified_baz, env.get_modification_time("baz.txt").unwrap());
+
+    // force should rewrite
+    env.cfg.force = true;
+    assert!(env.run().is_ok());
+    assert_ne!(modified, env.get_modification_time("foo.txt").unwrap());
+    env.cfg.force = false;
+
+    // always dirty should rewrite only that file
+    let modified = env.get_modification_time("foo.txt").unwrap();
+    let modified_baz = env.get_modification_time("baz.txt").unwrap();
+    env.cfg.always_dirty = vec!["foo.txt.txtpp".to_string()];
+    assert!(env.run().is_ok());
+    assert_ne!(modified, env.get_modification_time("foo.txt").unwrap());
+    assert_eq!(modified_baz, env.get_modification_time("baz.txt").unwrap());
+    env.cfg.always_dirty = vec![];
+
+    // changing a plain include should rebuild
+    env.set_file("bar.txt", "bar2\n");
+    assert!(env.run().is_ok());
+    env.assert_file_eq("foo.txt", "foo.txt.expected2");
+
+    // changing a txtpp dependency should rebuild both
+    env.set_file("baz.txt.txtpp", "baz2\n");
+    assert!(env.run().is_ok());
+    env.assert_file_eq("foo.txt", "foo.txt.expected3");
+
+    // changing the output should rebuild, which needs force to overwrite the change
+    env.set_file("foo.txt", "changed");
+    assert!(env.run().is_err());
+    env.cfg.force = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("foo.txt", "foo.txt.expected3");
+    env.cfg.force = false;
+
+    // an invalid cache is reported as a warning and rebuilt
+    env.set_file(CACHE_FILE, "not json");
+    let observer = std::sync::Arc::new(EventCollector::default());
+    env.cfg.observer = Some(observer.clone());
+    assert!(env.run().is_ok());
+    let events = observer.0.lock().unwrap();
+    assert!(events
+        .iter()
+        .any(|e| e.starts_with("warning ignoring invalid cache file")));
+});
+
+testit!(tests__examples__watch, |env| {
+    let mut watcher = Watcher::new(env.cfg.clone()).unwrap();
+    // first poll builds everything
+    assert!(watcher.poll().unwrap());
+    env.assert_file_eq("a.txt", "a.txt.expected");
+    env.assert_file_eq("d.txt", "d.txt.txtpp");
+    assert!(!watcher.poll().unwrap());
+    let modified_d = env.get_modification_time("d.txt").unwrap();
+
+    // plain include changed
+    env.set_file("c.txt", "c2\n");
+    assert!(watcher.poll().unwrap());
+    env.assert_file_eq("a.txt", "a.txt.expected2");
+    assert_eq!(modified_d, env.get_modification_time("d.txt").unwrap());
+    assert!(!watcher.poll().unwrap());
+
+    // txtpp dependency changed, depender should be rebuilt
+    env.set_file("b.txt.txtpp", "b2\n");
+    assert!(watcher.poll().unwrap());
+    env.assert_file_eq("a.txt", "a.txt.expected3");
+    assert_eq!(modified_d, env.get_modification_time("d.txt").unwrap());
+
+    // new file is picked up
+    env.set_file("e.txt.txtpp", "e\n");
+    assert!(watcher.poll().unwrap());
+    env.assert_file_eq("e.txt", "e.txt.txtpp");
+
+    // watch only supports building
+    env.cfg.mode = Mode::Verify;
+    assert!(Watcher::new(env.cfg.clone()).is_err());
+});
+
+testit!(tests__examples__plan, |env| {
+    env.cfg.mode = Mode::Plan;
+    env.cfg.inputs = vec!["a.txt".to_string()];
+    // run directives are not executed, and nothing is written
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    assert_eq!(
+        report.plan.unwrap(),
+        "  1. b.txt.txtpp\n       output: b.txt\n  2. a.txt.txtpp\n       output: a.txt\n       temp: t.txt\n       depends on: b.txt\n"
+    );
+    env.assert_path_exists("a.txt", false);
+    env.assert_path_exists("b.txt", false);
+    env.assert_path_exists("t.txt", false);
+    // the graph can be printed instead of the plan
+    env.cfg.graph_format = Some(GraphFormat::Dot);
+    let dot = Txtpp::run(env.cfg.clone()).unwrap().plan.unwrap();
+    assert!(dot.starts_with("digraph txtpp {\n"));
+    assert!(dot.contains("\"a.txt.txtpp\" [style=bold];"));
+    assert!(dot.contains("\"b.txt\" -> \"a.txt.txtpp\";"));
+    env.cfg.graph_format = Some(GraphFormat::Json);
+    let json = Txtpp::run(env.cfg.clone()).unwrap().plan.unwrap();
+    let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(json["files"]["a.txt.txtpp"]["output"], "a.txt");
+    assert_eq!(
+        json["files"]["a.txt.txtpp"]["deps"],
+        serde_json::json!(["b.txt"])
+    );
+    env.assert_path_exists("a.txt", false);
+    env.cfg.graph_format = None;
+    // a target with `..` is matched with the `.txtpp` file that generates it
+    env.cfg.inputs = vec!["sub/e.txt".to_string()];
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    assert_eq!(
+        report.plan.unwrap().replace('\\', "/"),
+        "  1. b.txt.txtpp\n       output: b.txt\n  2. sub/e.txt.txtpp\n       output: sub/e.txt\n       depends on: b.txt\n"
+    );
+    // circular dependencies are still errors
+    env.cfg.inputs = vec!["c.txt".to_string()];
+    assert!(env.run().is_err());
+});
+
+testit!(tests__examples__verify_diff, |env| {
+    env.set_file("a.txt", "a\nx\nc\n");
+    env.cfg.mode = Mode::Verify;
+    env.cfg.verify_diff = true;
+    env.cfg.patch_file = Some("out.patch".into());
+    assert!(env.run().is_err());
+    env.assert_file_eq("out.patch", "patch.expected");
+    // verify still fails and doesn't write the output
+    assert!(env.run().is_err());
+    env.assert_file_eq("out.patch", "patch.expected");
+    // patch is emptied when verify passes
+    env.cfg.mode = Mode::Build;
+    assert!(env.run().is_ok());
+    env.cfg.mode = Mode::Verify;
+    assert!(env.run().is_ok());
+    env.set_file("empty.expected", "");
+    env.assert_file_eq("out.patch", "empty.expected");
+});
+
+testit!(tests__examples__keep_going, |env| {
+    // stops at the first error
+    assert!(env.run().is_err());
+    env.cfg.keep_going = true;
+    let err = env.run().unwrap_err();
+    let err = err.current_context();
+    assert_eq!(err.failures.len(), 1);
+    assert_eq!(err.failures[0].error.file, "b.txt.txtpp");
+    assert_eq!(err.skipped, vec!["a.txt.txtpp", "d.txt.txtpp"]);
+    // independent files are processed
+    env.assert_file_eq("c.txt", "c.txt.txtpp");
+    env.assert_file_eq("e.txt", "e.txt.txtpp");
+
+    // all stale outputs are reported when verifying
+    env.cfg.inputs = vec!["c.txt".to_string(), "e.txt".to_string()];
+    env.set_file("c.txt", "changed");
+    env.set_file("e.txt", "changed");
+    env.cfg.mode = Mode::Verify;
+    let err = env.run().unwrap_err();
+    let err = err.current_context();
+    let mut failed = err
+        .failures
+        .iter()
+        .map(|f| f.error.file.as_str())
+        .collect::<Vec<_>>();
+    failed.sort();
+    assert_eq!(failed, vec!["c.txt.txtpp", "e.txt.txtpp"]);
+    assert!(err.skipped.is_empty());
+});
+
+testit!(tests__examples__depfile, |env| {
+    env.cfg.depfile = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("a.txt.d", "a.txt.d.expected");
+    env.assert_file_eq("b.txt.d", "b.txt.d.expected");
+    env.cfg.mode = Mode::Clean;
+    assert!(env.run().is_ok());
+    env.assert_path_exists("a.txt.d", false);
+    env.assert_path_exists("b.txt.d", false);
+});
+
+testit!(tests__examples__out_dir, |env| {
+    env.cfg.out_dir = Some("build/gen".into());
+    env.cfg.recursive = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("build/gen/a.txt", "a.txt.expected");
+    env.assert_file_eq("build/gen/sub/b.txt", "sub/b.txt.txtpp");
+    env.assert_path_exists("a.txt", false);
+    env.assert_path_exists("sub/b.txt", false);
+    // outputs can be specified in the output directory
+    env.cfg.inputs = vec!["build/gen/a.txt".to_string()];
+    env.cfg.mode = Mode::Verify;
+    assert!(env.run().is_ok());
+    env.cfg.inputs = vec![".".to_string()];
+    assert!(env.run().is_ok());
+    env.set_file("build/gen/sub/b.txt", "changed");
+    assert!(env.run().is_err());
+    env.cfg.mode = Mode::Clean;
+    assert!(env.run().is_ok());
+    env.assert_path_exists("build/gen/a.txt", false);
+    env.assert_path_exists("build/gen/sub/b.txt", false);
+    env.assert_path_exists("c.txt", true);
+});
+
+testit!(tests__examples__expand, |env| {
+    let input: &[u8] = b"hello\n  TXTPP#include bar.txt\nworld\n";
+    let output = std::fs::File::create(env.cfg.base_dir.join("out.txt")).unwrap();
+    assert!(expand(input, "<stdin>", output, &env.cfg).is_ok());
+    env.assert_file_eq("out.txt", "out.txt.expected");
+    // no output file is written for the input
+    env.assert_path_exists("bar", false);
+    let input: &[u8] = b"TXTPP#include missing.txt\n";
+    assert!(expand(input, "<stdin>", std::io::sink(), &env.cfg).is_err());
+});
+
+#[cfg(not(windows))]
+#[test]
+fn memory_fs() {
+    use std::sync::Arc;
+    use txtpp::fs::MemoryFs;
+
+    let fs = MemoryFs::new();
+    fs.insert("/project/a.txt.txtpp", "TXTPP#include b.txt\n");
+    fs.insert("/project/sub/b.txt.txtpp", "b\n");
+    let mut cfg = Config {
+        base_dir: "/project".into(),
+        inputs: vec!["a.txt".to_string(), "sub".to_string()],
+        verbosity: Verbosity::Quiet,
+        fs: Arc::new(fs.clone()),
+        ..Default::default()
+    };
+    // b.txt is resolved relative to the .txtpp file
+    assert!(txtpp(cfg.clone()).is_err());
+    fs.insert("/project/a.txt.txtpp", "TXTPP#include sub/b.txt\n");
+    assert!(txtpp(cfg.clone()).is_ok());
+    assert_eq!(fs.get_string("/project/a.txt").unwrap(), "b\n\n");
+    assert_eq!(fs.get_string("/project/sub/b.txt").unwrap(), "b\n");
+
+    cfg.mode = Mode::Verify;
+    assert!(txtpp(cfg.clone()).is_ok());
+    fs.insert("/project/a.txt", "changed");
+    assert!(txtpp(cfg.clone()).is_err());
+
+    cfg.mode = Mode::Clean;
+    assert!(txtpp(cfg.clone()).is_ok());
+    assert_eq!(
+        fs.files(),
+        vec![
+            std::path::PathBuf::from("/project").join(CACHE_FILE),
+            std::path::PathBuf::from("/project/a.txt.txtpp"),
+            std::path::PathBuf::from("/project/sub/b.txt.txtpp"),
+        ]
+    );
+}
+
+testit!(tests__examples__run_report, |env| {
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    let files = report
+        .files
+        .iter()
+        .map(|f| {
+            let input = f.input.file_name().unwrap().to_str().unwrap();
+            (input, f.status)
+        })
+        .collect::<Vec<_>>();
+    // b is a dependency of a, so it finishes first
+    assert_eq!(
+        files,
+        vec![
+            ("b.txt.txtpp", FileStatus::Written),
+            ("a.txt.txtpp", FileStatus::Written)
+        ]
+    );
+    let a = &report.files[1];
+    assert!(a.output.ends_with("a.txt"));
+    assert_eq!(a.deps.len(), 1);
+    assert!(a.deps[0].ends_with("b.txt"));
+    assert_eq!(a.temps.len(), 1);
+    assert!(a.temps[0].ends_with("t.txt"));
+
+    env.cfg.mode = Mode::InMemoryBuild;
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    assert_eq!(report.with_status(FileStatus::Unchanged).count(), 2);
+
+    env.cfg.mode = Mode::Build;
+    env.cfg.incremental = true;
+    assert!(env.run().is_ok());
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    assert_eq!(report.with_status(FileStatus::Fresh).count(), 2);
+
+    env.cfg.incremental = false;
+    env.cfg.mode = Mode::Verify;
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    assert_eq!(report.with_status(FileStatus::Verified).count(), 2);
+});
+
+#[derive(Debug, Default)]
+struct EventCollector(std::sync::Mutex<Vec<String>>);
+
+impl Observer for EventCollector {
+    fn notify(&self, event: &Event) {
+        let event = match event {
+            Event::FileStarted { input, .. } => {
+                format!("started {}", input.file_name().unwrap().to_str().unwrap())
+            }
+            Event::DirectiveExecuted {
+                line, directive, ..
+            } => {
+                format!("directive {line} {directive}")
+            }
+            Event::FileFinished { file } => format!("finished {:?}", file.status),
+            Event::Finished { report, .. } => format!("done {}", report.files.len()),
+            Event::Warning { message } => format!("warning {message}"),
+            _ => return,
+        };
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+testit!(tests__examples__observer, |env| {
+    let observer = std::sync::Arc::new(EventCollector::default());
+    env.cfg.observer = Some(observer.clone());
+    assert!(env.run().is_ok());
+    let events = observer.0.lock().unwrap();
+    assert_eq!(events.first().unwrap(), "started a.txt.txtpp");
+    assert!(events
+        .iter()
+        .any(|e| e == "directive 1 -TXTPP#include b.txt"));
+    assert!(events.contains(&"finished Written".to_string()));
+    assert_eq!(events.last().unwrap(), "done 1");
+});
+
+testit!(tests__examples__ignore, |env| {
+    env.cfg.recursive = true;
+    env.set_file(".gitignore", "third_party\n");
+    assert!(env.run().is_ok());
+    env.assert_path_exists("a.txt", true);
+    env.assert_path_exists("docs/c.md", true);
+    env.assert_path_exists("docs/d.txt", true);
+    env.assert_path_exists("third_party/e.txt", true);
+    // ignored by .txtppignore
+    env.assert_path_exists("node_modules/b.txt", false);
+
+    env.cfg.mode = Mode::Clean;
+    assert!(env.run().is_ok());
+    env.cfg.mode = Mode::Build;
+    env.cfg.gitignore = true;
+    env.cfg.include = vec!["*.txt.txtpp".to_string()];
+    env.cfg.exclude = vec!["/a.txt.txtpp".to_string()];
+    assert!(env.run().is_ok());
+    env.assert_path_exists("a.txt", false);
+    env.assert_path_exists("docs/c.md", false);
+    env.assert_path_exists("docs/d.txt", true);
+    env.assert_path_exists("third_party/e.txt", false);
+    env.assert_path_exists("node_modules/b.txt", false);
+
+    // files in the inputs are not filtered
+    env.cfg.inputs = vec!["a.txt.txtpp".to_string()];
+    assert!(env.run().is_ok());
+    env.assert_path_exists("a.txt", true);
+});
+
+#[cfg(unix)]
+testit!(tests__examples__symlink, |env| {
+    use std::os::unix::fs::symlink;
+    let base = env.cfg.base_dir.clone();
+    symlink("..", base.join("sub/loop")).unwrap();
+    symlink("c.txt", base.join("inc.txt")).unwrap();
+    symlink("a.txt.txtpp", base.join("link.txt.txtpp")).unwrap();
+    env.cfg.recursive = true;
+
+    // the loop is skipped, and the linked file is the same file
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    assert_eq!(report.files.len(), 2);
+    env.assert_path_exists("a.txt", true);
+    env.assert_path_exists("sub/b.txt", true);
+
+    env.cfg.symlinks = SymlinkPolicy::Follow;
+    assert!(env.run().is_err());
+    env.cfg.max_depth = Some(1);
+    assert!(env.run().is_ok());
+    env.cfg.max_depth = Some(0);
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    assert_eq!(report.files.len(), 1);
+
+    env.cfg.max_depth = None;
+    env.cfg.symlinks = SymlinkPolicy::Skip;
+    // include target is a link
+    assert!(env.run().is_err());
+    env.cfg.inputs = vec!["sub".to_string()];
+    assert!(env.run().is_ok());
+    env.cfg.inputs = vec!["link.txt.txtpp".to_string()];
+    assert!(env.run().is_err());
+});
+
+testit!(tests__examples__chain, |env| {
+    // every file depends on the next one, so they are processed one wave after another
+    for i in 0..20 {
+        env.set_file(
+            &format!("f{i}.txt.txtpp"),
+            &format!("-TXTPP#include f{}.txt\n{i}\n", i + 1),
+        );
+    }
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    // each file finishes after the file it depends on
+    let names = report
+        .files
+        .iter()
+        .map(|file| {
+            file.input
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    let expected = (0..=20)
+        .rev()
+        .map(|i| format!("f{i}.txt.txtpp"))
+        .collect::<Vec<_>>();
+    assert_eq!(names, expected);
+    let f0 = std::fs::read_to_string(env.cfg.base_dir.join("f0.txt")).unwrap();
+    let expected = std::iter::once("end".to_string())
+        .chain((0..20).rev().map(|i| i.to_string()))
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    assert_eq!(f0, expected);
+});
+
+testit!(tests__examples__atomic, |env| {
+    let tmp_files = |env: &ItEnv| {
+        std::fs::read_dir(&env.cfg.base_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.ends_with(".txtpp-tmp"))
+            .collect::<Vec<_>>()
+    };
+    env.set_file("a.txt", "previous\n");
+    // the run directive fails after the first line is processed
+    assert!(env.run().is_err());
+    let output = std::fs::read_to_string(env.cfg.base_dir.join("a.txt")).unwrap();
+    assert_eq!(output, "previous\n");
+    assert!(tmp_files(env).is_empty());
+    env.assert_path_exists("t.txt", true);
+
+    env.cfg.mode = Mode::InMemoryBuild;
+    assert!(env.run().is_err());
+    let output = std::fs::read_to_string(env.cfg.base_dir.join("a.txt")).unwrap();
+    assert_eq!(output, "previous\n");
+
+    env.cfg.mode = Mode::Build;
+    env.set_file(
+        "a.txt.txtpp",
+        "first\n-TXTPP#temp t.txt\n-t\nmiddle\nlast\n",
+    );
+    assert!(env.run().is_ok());
+    let output = std::fs::read_to_string(env.cfg.base_dir.join("a.txt")).unwrap();
+    assert_eq!(output, "first\nmiddle\nlast\n");
+    assert!(tmp_files(env).is_empty());
+});
+
+#[cfg(unix)]
+testit!(tests__examples__parallel, |env| {
+    env.cfg.parallel_run = true;
+    // the commands in each group wait for each other, so they only finish if they run concurrently
+    assert!(env.run().is_ok());
+    let output = std::fs::read_to_string(env.cfg.base_dir.join("a.txt")).unwrap();
+    assert_eq!(
+        output,
+        "begin\none\n1\ntwo\n2\n3\ntemp\n4\nfive\n5\nseq\nend\n"
+    );
+
+    env.set_file(
+        "a.txt.txtpp",
+        "a\n-TXTPP#run echo ok\nb\n-TXTPP#run exit 3\nc\n",
+    );
+    let error = env.run().unwrap_err();
+    let error = error.downcast_ref::<txtpp::error::PpError>().unwrap();
+    assert_eq!(error.line, 4);
+
+    // at most `num_threads` commands run at the same time, so `mkdir` never sees the other lock
+    env.cfg.num_threads = 1;
+    env.set_file(
+        "a.txt.txtpp",
+        "-TXTPP#run mkdir lock && sleep 0.1 && rmdir lock\na\n-TXTPP#run mkdir lock && sleep 0.1 && rmdir lock\n",
+    );
+    assert!(env.run().is_ok());
+});
+
+testit!(tests__examples__source_map, |env| {
+    env.cfg.source_map = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("a.txt.map", "a.txt.map.expected");
+    let map = std::fs::read_to_string(env.cfg.base_dir.join("a.txt.map")).unwrap();
+    let map: SourceMap = serde_json::from_str(&map).unwrap();
+    assert_eq!(
+        map.mappings[1].origin,
+        Origin::Include {
+            file: "sub/inc.txt".to_string(),
+            line: 1,
+        }
+    );
+    env.cfg.mode = Mode::Clean;
+    assert!(env.run().is_ok());
+    env.assert_path_exists("a.txt.map", false);
+});
+
+testit!(tests__examples__line_markers, |env| {
+    env.cfg.line_markers = [("c".to_string(), DEFAULT_LINE_MARKER.to_string())].into();
+    assert!(env.run().is_ok());
+    env.assert_file_eq("a.c", "a.c.expected");
+    env.assert_file_eq("b.txt", "b.txt.expected");
+
+    // markers are not compared when verifying
+    env.cfg.mode = Mode::Verify;
+    assert!(env.run().is_ok());
+    let output = std::fs::read_to_string(env.cfg.base_dir.join("a.c")).unwrap();
+    env.set_file("a.c", &output.replace("\"a.c.txtpp\"", "\"src/a.c.txtpp\""));
+    assert!(env.run().is_ok());
+    env.set_file("a.c", &output.replace("return 0;", "return 1;"));
+    assert!(env.run().is_err());
+    env.cfg.verify_diff = true;
+    assert!(env.run().is_err());
+});
+
+testit!(tests__examples__banner, |env| {
+    env.cfg.banner = Some(DEFAULT_BANNER.to_string());
+    assert!(env.run().is_ok());
+    env.assert_file_eq("run.sh", "run.sh.expected");
+    env.assert_file_eq("a.rs", "a.rs.expected");
+    env.assert_file_eq("b.html", "b.html.expected");
+    env.assert_file_eq("c.txt", "c.txt.txtpp");
+
+    env.cfg.mode = Mode::Verify;
+    assert!(env.run().is_ok());
+    env.set_file("a.rs", "fn main() {}\n");
+    assert!(env.run().is_err());
+    env.cfg.mode = Mode::InMemoryBuild;
+    env.cfg.force = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("a.rs", "a.rs.expected");
+});
+
+testit!(tests__examples__edited_output, |env| {
+    env.cfg.keep_going = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("a.txt", "a.txt.txtpp");
+
+    // an output changed by hand is not overwritten
+    env.set_file("a.txt", "changed\n");
+    let err = env.run().unwrap_err();
+    let err = err.current_context();
+    assert_eq!(err.failures.len(), 1);
+    assert_eq!(err.failures[0].error.kind, error::PpErrorKind::EditedOutput);
+    assert_eq!(err.failures[0].error.file, "a.txt.txtpp");
+    env.assert_file_eq("a.txt", "a.txt.changed");
+    env.assert_file_eq("b.txt", "b.txt.txtpp");
+    env.cfg.mode = Mode::InMemoryBuild;
+    assert!(env.run().is_err());
+    env.assert_file_eq("a.txt", "a.txt.changed");
+
+    // the change is kept if it's also made to the source
+    env.cfg.mode = Mode::Build;
+    env.set_file("a.txt.txtpp", "changed\n");
+    assert!(env.run().is_ok());
+    env.assert_file_eq("a.txt", "a.txt.changed");
+
+    // force overwrites the change
+    env.set_file("a.txt", "changed again\n");
+    assert!(env.run().is_err());
+    env.cfg.force = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("a.txt", "a.txt.changed");
+    env.cfg.force = false;
+
+    // deleted outputs are generated again
+    env.delete_file("a.txt");
+    assert!(env.run().is_ok());
+    env.assert_file_eq("a.txt", "a.txt.changed");
+});
+
+#[cfg(unix)]
+testit!(tests__examples__edited_output_after_error, |env| {
+    assert!(env.run().is_ok());
+    // b.txt is still being written when a.txt fails
+    env.set_file("a.txt.txtpp", "TXTPP#include missing.txt\n");
+    env.set_file("b.txt.txtpp", "-TXTPP#run sleep 0.3\nv2\n");
+    assert!(env.run().is_err());
+    // the output written after the error is recorded, so it's not seen as changed by hand
+    env.set_file("a.txt.txtpp", "a\n");
+    env.set_file("b.txt.txtpp", "-TXTPP#run sleep 0.3\nv3\n");
+    assert!(env.run().is_ok());
+});
+
+testit!(tests__examples__orphans, |env| {
+    env.cfg.depfile = true;
+    assert!(env.run().is_ok());
+    env.assert_path_exists("a.txt", true);
+    env.assert_path_exists("t.txt", true);
+    env.delete_file("a.txt.txtpp");
+
+    env.cfg.mode = Mode::Clean;
+    env.cfg.orphans = true;
+    env.cfg.dry_run = true;
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(report.with_status(FileStatus::Planned).count(), 1);
+    let names = report
+        .orphan_files
+        .iter()
+        .map(|file| file.file_name().unwrap().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["a.txt", "a.txt.d", "t.txt"]);
+    env.assert_path_exists("a.txt", true);
+    env.assert_path_exists("a.txt.d", true);
+    env.assert_path_exists("t.txt", true);
+
+    // outputs changed by hand need force
+    env.cfg.dry_run = false;
+    env.set_file("a.txt", "changed\n");
+    assert!(env.run().is_err());
+    env.assert_path_exists("a.txt", true);
+    env.assert_path_exists("t.txt", true);
+    env.cfg.force = true;
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    assert_eq!(report.with_status(FileStatus::Cleaned).count(), 1);
+    assert_eq!(report.orphan_files.len(), 3);
+    env.assert_path_exists("a.txt", false);
+    env.assert_path_exists("a.txt.d", false);
+    env.assert_path_exists("t.txt", false);
+    // outputs of existing files are kept
+    env.assert_file_eq("b.txt", "b.txt.txtpp");
+    env.assert_path_exists("b.txt.d", true);
+
+    // the orphan is removed from the cache
+    let report = Txtpp::run(env.cfg.clone()).unwrap();
+    assert!(report.files.is_empty());
+});
+
+#[cfg(unix)]
+testit!(tests__examples__run_subdir, |env| {
+    // no cache file is left when nothing is processed
+    assert!(env.run().is_ok());
+    env.assert_path_exists(CACHE_FILE, false);
+    // the base directory is not the current directory
+    env.cfg.recursive = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("sub/a.txt", "sub/a.txt.expected");
+});
+
+#[cfg(unix)]
+testit!(tests__examples__config_file, |env| {
+    // the files are not loaded unless enabled
+    env.cfg.inputs = vec!["a.txt.txtpp".to_string()];
+    assert!(env.run().is_ok());
+    let output = std::fs::read_to_string(env.cfg.base_dir.join("a.txt")).unwrap();
+    assert_eq!(output, "\n\n");
+
+    env.cfg.inputs = vec![".".to_string()];
+    env.cfg.config_files = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("a.txt", "a.txt.expected");
+    env.assert_file_eq("sub/b.txt", "sub/b.txt.expected");
+    env.assert_file_eq("other/d.txt", "other/d.txt.expected");
+    env.assert_path_exists("skip/c.txt", false);
+
+    // overrides take precedence over all files
+    env.cfg.overrides.defines = vec!["CONFIG_NAME=cli".to_string()];
+    assert!(env.run().is_ok());
+    let output = std::fs::read_to_string(env.cfg.base_dir.join("sub/b.txt")).unwrap();
+    assert_eq!(output, "cli\n");
+
+    // the file is discovered from an ancestor of the base directory
+    env.cfg.overrides = ConfigFile::default();
+    env.cfg.base_dir = env.cfg.base_dir.join("other");
+    env.set_file("other/d.txt", "");
+    env.cfg.force = true;
+    assert!(env.run().is_ok());
+    env.assert_file_eq("other/d.txt", "other/d.txt.expected");
+});
+
+#[cfg(feature = "cli")]
+testit!(tests__examples__message_format, |env| {
+    let run = |env: &ItEnv, args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_txtpp"))
+            .args(args)
+            .args(["--message-format", "json"])
+            .current_dir(&env.cfg.base_dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let messages = run(env, &[]);
+    assert_eq!(messages[0]["reason"], "started");
+    assert_eq!(messages[0]["mode"], "build");
+    let processed = messages
+        .iter()
+        .find(|m| m["reason"] == "processed")
+        .unwrap();
+    assert_eq!(processed["status"], "written");
+    let finished = messages.last().unwrap();
+    assert_eq!(finished["reason"], "finished");
+    assert_eq!(finished["success"], true);
+    assert_eq!(finished["files"], 1);
+    env.assert_file_eq("a.txt", "a.txt.txtpp");
+
+    let messages = run(env, &["--needed"]);
+    assert_eq!(messages[0]["mode"], "in-memory-build");
+    let processed = messages
+        .iter()
+        .find(|m| m["reason"] == "processed")
+        .unwrap();
+    assert_eq!(processed["status"], "unchanged");
+});
+
+#[cfg(all(unix, feature = "cli"))]
+testit!(tests__examples__expand_config, |env| {
+    let expand = |env: &ItEnv, args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_txtpp"))
+            .arg("expand")
+            .args(args)
+            .arg("a.txtpp")
+            .current_dir(&env.cfg.base_dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(expand(env, &[]), "toml\n\n");
+    assert_eq!(expand(env, &["--no-config"]), "\n\n");
+});